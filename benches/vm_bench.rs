@@ -0,0 +1,49 @@
+//! Benchmarks of the VM loops: the compact codes (`vm::run`) against the ByteCode loop (`vm::run_unpacked`),
+//! and the register VM (`regvm::run`)
+//! Run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use nadesiko4::bytecode::NakoSystem;
+use nadesiko4::{compile, regvm, vm, NakoOptions};
+
+const ROUNDS: u32 = 20;
+
+const PROGRAMS: [(&str, &str); 3] = [
+    ("while_arith", "A=0\nI=0\nI<100000の間\nA=A+I*2-1\nI=I+1\nここまで"),
+    ("count_loop", "A=0\n100000回\nA=A+回数*回数\nここまで"),
+    ("compare_if", "A=0\nI=0\nI<100000の間\nもしI>50000ならばA=A+1\n違えばA=A-1\nI=I+1\nここまで"),
+];
+
+/// Average time of running a fresh copy of the compiled system
+fn measure(sys: &NakoSystem, run: fn(&mut NakoSystem) -> Result<(), nadesiko4::error::NakoError>) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let mut sys = sys.clone();
+        let time = Instant::now();
+        run(black_box(&mut sys)).unwrap();
+        total += time.elapsed();
+    }
+    total / ROUNDS
+}
+
+fn main() {
+    for (name, code) in PROGRAMS {
+        let sys = match compile(code, &NakoOptions::new()) {
+            Ok(sys) => sys,
+            Err(err) => {
+                println!("{:<12} skipped: {}", name, err);
+                continue;
+            }
+        };
+        // warm up
+        measure(&sys, vm::run);
+        let unpacked = measure(&sys, vm::run_unpacked);
+        let compact = measure(&sys, vm::run);
+        let register = measure(&sys, regvm::run);
+        println!("{:<12} bytecode {:>10.3?}  compact {:>10.3?} x{:.2}  register {:>10.3?} x{:.2}",
+            name, unpacked, compact, unpacked.as_secs_f64() / compact.as_secs_f64(),
+            register, unpacked.as_secs_f64() / register.as_secs_f64());
+    }
+}
@@ -0,0 +1,452 @@
+//! asm module
+//! Disassembles VM codes into text with names and labels, and assembles the text back.
+//!
+//! ```text
+//! .func 倍増 L0001 args=を locals=倍増:N,倍増:それ
+//!     Jump L0005
+//! L0001:
+//! .line 2  ; N*2で戻る
+//!     PushVariable 倍増:N
+//!     PushConst 2
+//!     Mul
+//!     Return
+//! L0005:
+//! ```
+//!
+//! - `.func <name> <label> args=<josi>,... locals=<var>,...` declares a user function
+//!   (josi of one argument are joined with `|`, and `_` means no josi)
+//! - `.line <n>` sets the source line of the following codes
+//! - `<label>:` names the position of the next code
+//! - operands are constants (`1`, `"abc"`, `true`, `none`, `[1, "a"]`), variable names,
+//!   function names, labels and numbers, or a raw index such as `#3`
+//! - `;` starts a comment
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::bytecode::{ByteCode, ByteCodeKind, NakoSystem, Operand};
+use crate::lexer;
+use crate::source::SourcePos;
+use crate::value::Value;
+
+/// Error of assembling, with the line number of the text (from 1)
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[アセンブルエラー] {}行目: {}", self.line, self.message)
+    }
+}
+impl std::error::Error for AsmError {}
+
+/// Disassemble all codes of the system. With the source, each line is annotated with its text.
+pub fn disassemble(sys: &NakoSystem, source: Option<&str>) -> String {
+    disassemble_from(sys, 0, source)
+}
+
+/// Disassemble the codes from `start` (such as the codes added by `compile_into`)
+pub fn disassemble_from(sys: &NakoSystem, start: usize, source: Option<&str>) -> String {
+    let start = start.min(sys.codes.len());
+    let funcs: Vec<_> = sys.func_table.funcs.iter()
+        .filter(|func| func.sys_func.is_none() && func.addr >= start)
+        .collect();
+    // positions to name: jump targets and function bodies
+    let mut labels: BTreeSet<usize> = funcs.iter().map(|func| func.addr).collect();
+    for code in &sys.codes[start..] {
+        for (op, arg) in code.kind.operands().iter().zip(args(code)) {
+            if *op == Operand::Addr {
+                labels.insert(arg);
+            }
+        }
+    }
+    let mut out = String::new();
+    for func in funcs {
+        let josi: Vec<String> = func.josi_list.iter()
+            .map(|josi| josi.iter().map(|j| if j.is_empty() { "_" } else { j.as_str() }).collect::<Vec<_>>().join("|"))
+            .collect();
+        let locals: Vec<String> = func.local_vars.iter().map(|&index| var_text(sys, index)).collect();
+        out.push_str(&format!(".func {} {} args={} locals={}\n", func.display_name, label(func.addr), josi.join(","), locals.join(",")));
+    }
+    let src_lines: Vec<&str> = source.map(|src| src.lines().collect()).unwrap_or_default();
+    let mut last_line = None;
+    for (i, code) in sys.codes.iter().enumerate().skip(start) {
+        if labels.contains(&i) {
+            out.push_str(&format!("{}:\n", label(i)));
+        }
+        let line = sys.spans.get(i).map_or(0, |pos| pos.line);
+        if last_line != Some(line) {
+            last_line = Some(line);
+            match src_lines.get(line) {
+                Some(text) => out.push_str(&format!(".line {}  ; {}\n", line + 1, text.trim())),
+                None => out.push_str(&format!(".line {}\n", line + 1)),
+            }
+        }
+        out.push_str("    ");
+        out.push_str(&code.kind.name());
+        for (op, arg) in code.kind.operands().iter().zip(args(code)) {
+            out.push(' ');
+            out.push_str(&operand_text(sys, *op, arg));
+        }
+        out.push('\n');
+    }
+    if labels.contains(&sys.codes.len()) {
+        out.push_str(&format!("{}:\n", label(sys.codes.len())));
+    }
+    out
+}
+
+/// Assemble the text into a new system
+pub fn assemble(text: &str) -> Result<NakoSystem, AsmError> {
+    assemble_with(NakoSystem::new(), text)
+}
+
+/// Assemble the text into the given system, such as one with host functions registered
+pub fn assemble_with(mut sys: NakoSystem, text: &str) -> Result<NakoSystem, AsmError> {
+    // first pass: positions of the labels and declarations of the functions
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut funcs: Vec<(usize, String, String, Vec<String>)> = Vec::new();
+    let mut count = 0;
+    for (i, line) in text.lines().enumerate() {
+        let tokens = split_tokens(strip_comment(line)).map_err(|message| AsmError { line: i + 1, message })?;
+        let Some(first) = tokens.first() else { continue };
+        if let Some(name) = first.strip_suffix(':') && tokens.len() == 1 {
+            if labels.insert(name.to_string(), count).is_some() {
+                return Err(AsmError { line: i + 1, message: format!("ラベル『{}』が重複しています", name) });
+            }
+        } else if first == ".func" {
+            let err = |message: String| AsmError { line: i + 1, message };
+            let (name, addr) = match (tokens.get(1), tokens.get(2)) {
+                (Some(name), Some(addr)) => (name.clone(), addr.clone()),
+                _ => return Err(err("『.func』には関数名とラベルが必要です".to_string())),
+            };
+            let mut josi_list = Vec::new();
+            let mut locals = Vec::new();
+            for token in &tokens[3..] {
+                if let Some(args) = token.strip_prefix("args=") {
+                    josi_list = split_list(args).iter()
+                        .map(|josi| josi.split('|').map(|j| if j == "_" { String::new() } else { j.to_string() }).collect())
+                        .collect();
+                } else if let Some(vars) = token.strip_prefix("locals=") {
+                    locals = split_list(vars);
+                } else {
+                    return Err(err(format!("『{}』は『.func』に書けません", token)));
+                }
+            }
+            sys.func_table.register(&name, josi_list);
+            funcs.push((i + 1, name, addr, locals));
+        } else if !first.starts_with('.') {
+            count += 1;
+        }
+    }
+    // function bodies and local variables
+    for (line, name, addr, locals) in funcs {
+        let addr = resolve_label(&labels, &addr).map_err(|message| AsmError { line, message })?;
+        let local_vars = locals.iter().map(|var| var_index(&mut sys, var)).collect();
+        if let Some(index) = sys.func_table.get_name_index(&lexer::normalize_word(&name)) {
+            sys.func_table.funcs[index].addr = addr;
+            sys.func_table.funcs[index].local_vars = local_vars;
+        }
+    }
+    // second pass: codes
+    let mut pos = SourcePos::zero();
+    for (i, line) in text.lines().enumerate() {
+        let err = |message: String| AsmError { line: i + 1, message };
+        let tokens = split_tokens(strip_comment(line)).map_err(err)?;
+        let Some(first) = tokens.first() else { continue };
+        if first == ".line" {
+            let n = tokens.get(1).and_then(|n| n.parse::<usize>().ok()).filter(|&n| n > 0)
+                .ok_or_else(|| err("『.line』には1以上の行番号が必要です".to_string()))?;
+            pos = SourcePos::new(n - 1, 0);
+            continue;
+        }
+        if first.starts_with('.') || (first.ends_with(':') && tokens.len() == 1) {
+            if first.starts_with('.') && first != ".func" {
+                return Err(err(format!("『{}』は不明な指示です", first)));
+            }
+            continue;
+        }
+        let kind = ByteCodeKind::from_name(first).ok_or_else(|| err(format!("『{}』は不明な命令です", first)))?;
+        let ops = kind.operands();
+        if tokens.len() - 1 != ops.len() {
+            return Err(err(format!("『{}』の引数は{}個です", first, ops.len())));
+        }
+        let mut args = [0usize; 3];
+        for (slot, (op, token)) in ops.iter().zip(&tokens[1..]).enumerate() {
+            args[slot] = operand_value(&mut sys, &labels, *op, token).map_err(err)?;
+        }
+        sys.codes.push(ByteCode::new(kind, args[0], args[1], args[2]));
+        sys.spans.push(pos);
+    }
+    Ok(sys)
+}
+
+fn args(code: &ByteCode) -> [usize; 3] {
+    [code.arg1, code.arg2, code.arg3]
+}
+
+fn label(addr: usize) -> String {
+    format!("L{:04}", addr)
+}
+
+/// Operand as text, or a raw index when it can not be named
+fn operand_text(sys: &NakoSystem, op: Operand, arg: usize) -> String {
+    match op {
+        Operand::Const => sys.const_list.get(arg).map_or_else(|| format!("#{}", arg), literal),
+        Operand::Var => var_text(sys, arg),
+        Operand::Func => match sys.func_table.get_by_index(arg) {
+            Some(func) if is_name(&func.display_name) => func.display_name.clone(),
+            _ => format!("#{}", arg),
+        },
+        Operand::Addr => label(arg),
+        Operand::Count | Operand::Line => arg.to_string(),
+    }
+}
+
+fn var_text(sys: &NakoSystem, index: usize) -> String {
+    match sys.var_table.vars.get(index) {
+        Some(var) if is_name(&var.name) => var.name.clone(),
+        _ => format!("#{}", index),
+    }
+}
+
+/// Can the name be written as it is?
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(['#', '.', '"', '['])
+        && !name.contains(|c: char| c.is_whitespace() || c == ';' || c == ',')
+}
+
+/// Constant as text that `parse_literal` reads back
+fn literal(value: &Value) -> String {
+    match value {
+        Value::None => "none".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => {
+            let mut text = String::from("\"");
+            for c in s.chars() {
+                match c {
+                    '"' => text.push_str("\\\""),
+                    '\\' => text.push_str("\\\\"),
+                    '\n' => text.push_str("\\n"),
+                    '\r' => text.push_str("\\r"),
+                    '\t' => text.push_str("\\t"),
+                    _ => text.push(c),
+                }
+            }
+            text.push('"');
+            text
+        },
+        Value::Array(items) => format!("[{}]", items.iter().map(literal).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+/// Remove the comment (`;` outside of strings)
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {},
+        }
+    }
+    line
+}
+
+/// Split the line by spaces, keeping strings and arrays in one token
+fn split_tokens(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        if in_string {
+            token.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {},
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ if c.is_whitespace() && depth == 0 => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+                continue;
+            },
+            _ => {},
+        }
+        token.push(c);
+    }
+    if in_string {
+        return Err("文字列が閉じられていません".to_string());
+    }
+    if depth != 0 {
+        return Err("配列の『[』と『]』が対応していません".to_string());
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Split `a,b,c` (empty for an empty list)
+fn split_list(text: &str) -> Vec<String> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    text.split(',').map(|s| s.to_string()).collect()
+}
+
+/// Raw index written as `#3`
+fn raw_index(token: &str) -> Option<usize> {
+    token.strip_prefix('#').and_then(|n| n.parse().ok())
+}
+
+fn resolve_label(labels: &HashMap<String, usize>, token: &str) -> Result<usize, String> {
+    raw_index(token)
+        .or_else(|| labels.get(token).copied())
+        .ok_or_else(|| format!("ラベル『{}』が見つかりません", token))
+}
+
+fn var_index(sys: &mut NakoSystem, token: &str) -> usize {
+    raw_index(token).unwrap_or_else(|| sys.var_table.get_name_index_create(token))
+}
+
+/// Read the operand and return the value of the arg
+fn operand_value(sys: &mut NakoSystem, labels: &HashMap<String, usize>, op: Operand, token: &str) -> Result<usize, String> {
+    match op {
+        Operand::Const => {
+            if let Some(index) = raw_index(token) {
+                return Ok(index);
+            }
+            let value = parse_literal(token)?;
+            sys.const_list.push(value);
+            Ok(sys.const_list.len() - 1)
+        },
+        Operand::Var => Ok(var_index(sys, token)),
+        Operand::Func => raw_index(token)
+            .or_else(|| sys.func_table.get_name_index(&lexer::normalize_word(token)))
+            .ok_or_else(|| format!("関数『{}』が見つかりません", token)),
+        Operand::Addr => resolve_label(labels, token),
+        Operand::Count | Operand::Line => token.parse().map_err(|_| format!("『{}』は数ではありません", token)),
+    }
+}
+
+/// Read a constant written by `literal`
+fn parse_literal(token: &str) -> Result<Value, String> {
+    let chars: Vec<char> = token.chars().collect();
+    let mut pos = 0;
+    let value = read_literal(&chars, &mut pos)?;
+    if pos != chars.len() {
+        return Err(format!("定数『{}』が不正です", token));
+    }
+    Ok(value)
+}
+
+fn read_literal(chars: &[char], pos: &mut usize) -> Result<Value, String> {
+    while chars.get(*pos).is_some_and(|c| c.is_whitespace()) {
+        *pos += 1;
+    }
+    match chars.get(*pos) {
+        Some('"') => {
+            *pos += 1;
+            let mut s = String::new();
+            loop {
+                let c = *chars.get(*pos).ok_or("文字列が閉じられていません")?;
+                *pos += 1;
+                match c {
+                    '"' => return Ok(Value::String(s)),
+                    '\\' => {
+                        let e = *chars.get(*pos).ok_or("文字列が閉じられていません")?;
+                        *pos += 1;
+                        s.push(match e {
+                            'n' => '\n',
+                            'r' => '\r',
+                            't' => '\t',
+                            _ => e,
+                        });
+                    },
+                    _ => s.push(c),
+                }
+            }
+        },
+        Some('[') => {
+            *pos += 1;
+            let mut items = Vec::new();
+            loop {
+                while chars.get(*pos).is_some_and(|c| c.is_whitespace()) {
+                    *pos += 1;
+                }
+                if chars.get(*pos) == Some(&']') {
+                    *pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                if !items.is_empty() {
+                    if chars.get(*pos) != Some(&',') {
+                        return Err("配列の要素は『,』で区切ってください".to_string());
+                    }
+                    *pos += 1;
+                }
+                items.push(read_literal(chars, pos)?);
+            }
+        },
+        _ => {
+            let start = *pos;
+            while chars.get(*pos).is_some_and(|&c| c != ',' && c != ']' && !c.is_whitespace()) {
+                *pos += 1;
+            }
+            let word: String = chars[start..*pos].iter().collect();
+            match word.as_str() {
+                "none" => Ok(Value::None),
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => word.parse::<f64>().map(Value::Number).map_err(|_| format!("定数『{}』が不正です", word)),
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_round_trip() {
+        let values = [
+            Value::None, Value::Bool(false), Value::Number(-0.25), Value::Number(1e300),
+            Value::from_str("a \"b\"; c\\\n"), Value::Array(vec![Value::Number(1.0), Value::from_str("x, y"), Value::Array(vec![])]),
+        ];
+        for value in values {
+            assert_eq!(parse_literal(&literal(&value)), Ok(value));
+        }
+    }
+
+    #[test]
+    fn tokens_and_comments() {
+        assert_eq!(strip_comment("PushConst \";\" ; comment"), "PushConst \";\" ");
+        assert_eq!(split_tokens("  PushConst [1, \"a b\"]  ").unwrap(), vec!["PushConst", "[1, \"a b\"]"]);
+        assert!(split_tokens("PushConst \"abc").is_err());
+    }
+
+    #[test]
+    fn assemble_errors() {
+        assert_eq!(assemble("    Foo").unwrap_err().message, "『Foo』は不明な命令です");
+        assert_eq!(assemble("\n    Jump L9").unwrap_err(), AsmError { line: 2, message: "ラベル『L9』が見つかりません".to_string() });
+        assert_eq!(assemble("    Add 1").unwrap_err().message, "『Add』の引数は0個です");
+    }
+}
@@ -1,5 +1,5 @@
-/// AST module
-/// Defines the Abstract Syntax Tree (AST) structure.
+//! AST module
+//! Defines the Abstract Syntax Tree (AST) structure.
 
 use crate::source::SourcePos;
 use crate::value::Value;
@@ -13,12 +13,28 @@ pub enum AstKind {
     Number,
     String,
     Variable,
-    Print,
     Plus,
     Minus,
     Mul,
     Div,
     Let,
+    If,
+    Eq,
+    NotEq,
+    Gt,
+    GtEq,
+    Lt,
+    LtEq,
+    Times,
+    For,
+    While,
+    Break,
+    Continue,
+    Array,
+    Foreach,
+    FuncDef,
+    Call,
+    Return,
 }
 
 #[derive(Clone, Debug)]
@@ -27,6 +43,7 @@ pub struct AstNode {
     pub value: Value,
     pub children: Option<Vec<AstNode>>,
     pub pos: SourcePos,
+    pub josi: Option<String>,
 }
 impl AstNode {
     pub fn new_nop() -> Self {
@@ -35,6 +52,7 @@ impl AstNode {
             value: Value::None,
             children: None,
             pos: SourcePos::zero(),
+            josi: None,
         }
     }
     pub fn new(kind: AstKind) -> Self {
@@ -43,6 +61,7 @@ impl AstNode {
             value: Value::None,
             children: None,
             pos: SourcePos::zero(),
+            josi: None,
         }
     }
     pub fn new_pos(kind: AstKind, pos: SourcePos) -> Self {
@@ -51,6 +70,7 @@ impl AstNode {
             value: Value::None,
             children: None,
             pos,
+            josi: None,
         }
     }
     pub fn add_child(&mut self, child: AstNode) {
@@ -61,12 +81,14 @@ impl AstNode {
             children.push(child);
         }
     }
+    /// Print the tree to stderr for debugging
     pub fn print_tree(&self, indent: usize) {
         let indent_str = "  ".repeat(indent);
-        println!("{}AstNode: kind={:?}, value={:?}, pos=({:?})",
+        eprintln!("{}AstNode: kind={:?}, value={:?}, josi={:?}, pos=({:?})",
             indent_str,
             self.kind,
             self.value,
+            self.josi,
             self.pos,
         );
         if let Some(ref children) = self.children {
@@ -1,33 +1,161 @@
-/// ast_to_vmcode module
-/// Converts AST nodes to VM code instructions.
+//! ast_to_vmcode module
+//! Converts AST nodes to VM code instructions.
+
+use std::collections::HashMap;
 
 use crate::ast::{AstNode, AstKind};
 use crate::bytecode::{ByteCodeKind, ByteCode, NakoSystem};
+use crate::error::ErrorCode;
+use crate::lexer;
+use crate::source::SourcePos;
 use crate::value::Value;
 
+/// Local variables of the function being compiled
+#[derive(Clone, Debug, Default)]
+struct LocalScope {
+    func_index: usize,
+    /// variable name to variable index
+    name_map: HashMap<String, usize>,
+}
+
+/// Jump target used while compiling, which may be placed after the jumps to it
+#[derive(Clone, Debug, Default)]
+struct Label {
+    /// code index of the target, None until the label is placed
+    addr: Option<usize>,
+    /// indexes of the jump codes waiting for the target
+    refs: Vec<usize>,
+}
+
+/// Labels of the loop being compiled
+#[derive(Clone, Copy, Debug)]
+struct LoopContext {
+    /// label to jump for 抜ける
+    break_label: usize,
+    /// label to jump for 続ける
+    continue_label: usize,
+}
+
+/// State used only while compiling, dropped when the compile ends
+#[derive(Debug)]
+struct CompileContext {
+    /// loop contexts (innermost last)
+    loop_stack: Vec<LoopContext>,
+    /// jump labels (index is the label id)
+    labels: Vec<Label>,
+    /// local variables while compiling a function
+    local_scope: Option<LocalScope>,
+    /// position of the AST node being compiled
+    compile_pos: SourcePos,
+}
+impl CompileContext {
+    fn new() -> Self {
+        CompileContext {
+            loop_stack: Vec::new(),
+            labels: Vec::new(),
+            local_scope: None,
+            compile_pos: SourcePos::zero(),
+        }
+    }
+}
+
 /// Convert AST to VM code
 pub fn ast_to_bytecodes(ast: &AstNode) -> NakoSystem {
-    let mut sys = NakoSystem::new();
-    read_ast(&mut sys, ast);
+    ast_to_bytecodes_with(NakoSystem::new(), ast)
+}
+
+/// Convert AST to VM code using the given system (keeps its registered host functions)
+pub fn ast_to_bytecodes_with(mut sys: NakoSystem, ast: &AstNode) -> NakoSystem {
+    append_bytecodes(&mut sys, ast);
     sys
 }
 
+/// Append the VM code of the AST after the codes already in the system
+pub fn append_bytecodes(sys: &mut NakoSystem, ast: &AstNode) {
+    append_bytecodes_with(sys, &mut CompileContext::new(), ast);
+}
+
+fn append_bytecodes_with(sys: &mut NakoSystem, ctx: &mut CompileContext, ast: &AstNode) {
+    // register functions first so that they can be called before their definitions
+    register_funcs(sys, ast);
+    read_ast(sys, ctx, ast);
+    // every jump must have found its target
+    if ctx.labels.iter().any(|label| label.addr.is_none() && !label.refs.is_empty()) {
+        sys.compile_error(ErrorCode::InvalidAst, ast.pos, "ジャンプ先が決まっていません");
+    }
+}
+
+/// Register all user-defined functions in the AST to the function table
+fn register_funcs(sys: &mut NakoSystem, node: &AstNode) {
+    if node.kind == AstKind::FuncDef {
+        let name = node.value.to_string();
+        let josi_list = node.children.as_ref()
+            .and_then(|children| children.first())
+            .and_then(|args| args.children.as_ref())
+            .map(|args| args.iter().map(|arg| vec![arg.josi.clone().unwrap_or_default()]).collect())
+            .unwrap_or_default();
+        sys.func_table.register(&name, josi_list);
+        return;
+    }
+    if let Some(ref children) = node.children {
+        for child in children {
+            register_funcs(sys, child);
+        }
+    }
+}
+
 /// Read AST nodes recursively and generate VM code
-fn read_ast(sys: &mut NakoSystem, node: &AstNode) {
+fn read_ast(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    // codes generated so far belong to the parent node
+    let parent_pos = ctx.compile_pos;
+    sync_spans(sys, parent_pos);
+    // empty nodes made by the parser have no position
+    if node.kind != AstKind::Nop {
+        ctx.compile_pos = node.pos;
+    }
+    read_ast_node(sys, ctx, node);
+    let pos = ctx.compile_pos;
+    sync_spans(sys, pos);
+    ctx.compile_pos = parent_pos;
+}
+
+/// Record the source position of codes which have no position yet
+fn sync_spans(sys: &mut NakoSystem, pos: SourcePos) {
+    let len = sys.codes.len();
+    sys.spans.resize(len, pos);
+}
+
+fn read_ast_node(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
     match node.kind {
         AstKind::Nop => read_nop(sys, node),
         AstKind::Comment => read_comment(sys, node),
-        AstKind::Node => read_node(sys, node),
+        AstKind::Node => read_node(sys, ctx, node),
         AstKind::Number => read_number(sys, node),
         AstKind::String => read_string(sys, node),
-        AstKind::Variable => read_variable(sys, node),
-        AstKind::Print => read_print(sys, node),
-        AstKind::Plus => read_plus(sys, node),
-        AstKind::Minus => read_minus(sys, node),
-        AstKind::Mul => read_mul(sys, node),
-        AstKind::Div => read_div(sys, node),
+        AstKind::Variable => read_variable(sys, ctx, node),
+        AstKind::Plus => read_plus(sys, ctx, node),
+        AstKind::Minus => read_minus(sys, ctx, node),
+        AstKind::Mul => read_mul(sys, ctx, node),
+        AstKind::Div => read_div(sys, ctx, node),
         AstKind::EOS => read_eos(sys, node),
-        AstKind::Let => read_let(sys, node),
+        AstKind::Let => read_let(sys, ctx, node),
+        AstKind::If => read_if(sys, ctx, node),
+        AstKind::Eq => read_compare(sys, ctx, node, ByteCodeKind::Eq),
+        AstKind::NotEq => read_compare(sys, ctx, node, ByteCodeKind::NotEq),
+        AstKind::Gt => read_compare(sys, ctx, node, ByteCodeKind::Gt),
+        AstKind::GtEq => read_compare(sys, ctx, node, ByteCodeKind::GtEq),
+        AstKind::Lt => read_compare(sys, ctx, node, ByteCodeKind::Lt),
+        AstKind::LtEq => read_compare(sys, ctx, node, ByteCodeKind::LtEq),
+        AstKind::Times => read_times(sys, ctx, node),
+        AstKind::For => read_for(sys, ctx, node),
+        AstKind::While => read_while(sys, ctx, node),
+        AstKind::Break => read_break(sys, ctx, node),
+        AstKind::Continue => read_continue(sys, ctx, node),
+        AstKind::Array => read_array(sys, ctx, node),
+        AstKind::Foreach => read_foreach(sys, ctx, node),
+        AstKind::FuncDef => read_func_def(sys, ctx, node),
+        AstKind::Call => read_call(sys, ctx, node),
+        AstKind::Return => read_return(sys, ctx, node),
     }
 }
 
@@ -39,16 +167,12 @@ fn read_comment(sys: &mut NakoSystem, _node: &AstNode) {
     sys.codes.push(ByteCode::new_nop());
 }
 
-fn read_node(sys: &mut NakoSystem, node: &AstNode) {
-    read_ast_children(sys, node);
+fn read_node(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    read_ast_children(sys, ctx, node);
 }
 
 fn read_number(sys: &mut NakoSystem, node: &AstNode) {
-    let value = if let Some(num) = node.value.to_number() {
-        num
-    } else {
-        0.0f64
-    };
+    let value = node.value.to_number().unwrap_or(0.0f64);
     let index = sys.const_list.len();
     sys.const_list.push(Value::from_number(value));
     sys.codes.push(ByteCode::new(
@@ -71,9 +195,9 @@ fn read_string(sys: &mut NakoSystem, node: &AstNode) {
     ));
 }
 
-fn read_variable(sys: &mut NakoSystem, node: &AstNode) {
+fn read_variable(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
     let var_name = node.value.to_string();
-    let var_name_index = sys.var_table.get_name_index_create(&var_name);
+    let var_name_index = get_var_index(sys, ctx, &var_name);
     sys.codes.push(ByteCode::new(
         ByteCodeKind::PushVariable,
         var_name_index,
@@ -82,31 +206,31 @@ fn read_variable(sys: &mut NakoSystem, node: &AstNode) {
     ));
 }
 
-fn read_print(sys: &mut NakoSystem, node: &AstNode) {
-    read_ast_children(sys, node);
-    sys.codes.push(ByteCode::new(ByteCodeKind::Print, 0, 0, 0));
-}
-
-fn read_plus(sys: &mut NakoSystem, node: &AstNode) {
-    read_ast_children(sys, node);
+fn read_plus(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    read_ast_children(sys, ctx, node);
     sys.codes.push(ByteCode::new(ByteCodeKind::Add, 0, 0, 0));
 }
 
-fn read_minus(sys: &mut NakoSystem, node: &AstNode) {
-    read_ast_children(sys, node);
+fn read_minus(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    read_ast_children(sys, ctx, node);
     sys.codes.push(ByteCode::new(ByteCodeKind::Sub, 0, 0, 0));
 }
 
-fn read_mul(sys: &mut NakoSystem, node: &AstNode) {
-    read_ast_children(sys, node);
+fn read_mul(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    read_ast_children(sys, ctx, node);
     sys.codes.push(ByteCode::new(ByteCodeKind::Mul, 0, 0, 0));
 }
 
-fn read_div(sys: &mut NakoSystem, node: &AstNode) {
-    read_ast_children(sys, node);
+fn read_div(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    read_ast_children(sys, ctx, node);
     sys.codes.push(ByteCode::new(ByteCodeKind::Div, 0, 0, 0));
 }
 
+fn read_compare(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode, kind: ByteCodeKind) {
+    read_ast_children(sys, ctx, node);
+    sys.codes.push(ByteCode::new(kind, 0, 0, 0));
+}
+
 fn read_eos(sys: &mut NakoSystem, node: &AstNode) {
     sys.codes.push(ByteCode::new(
         ByteCodeKind::EOS,
@@ -117,24 +241,24 @@ fn read_eos(sys: &mut NakoSystem, node: &AstNode) {
 }
 
 /// Read AST children nodes
-fn read_ast_children(sys: &mut NakoSystem, node: &AstNode) {
+fn read_ast_children(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
     if let Some(ref children) = node.children {
         for child in children {
-            read_ast(sys, child);
+            read_ast(sys, ctx, child);
         }
     }
 }
 
-fn read_let(sys: &mut NakoSystem, node: &AstNode) {
+fn read_let(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
     // Assuming the first child is the variable name and the second child is the value expression
     if let Some(ref children) = node.children {
         if children.len() == 2 {
             let var_name_node = &children[0];
             let var_name = var_name_node.value.to_string();
-            let var_index = sys.var_table.get_name_index_create(&var_name);
+            let var_index = get_store_var_index(sys, ctx, &var_name);
             let value_node = &children[1];
             // Process the value expression first
-            read_ast(sys, value_node);
+            read_ast(sys, ctx, value_node);
             // Store the variable
             sys.codes.push(ByteCode::new(
                 ByteCodeKind::Let,
@@ -144,6 +268,410 @@ fn read_let(sys: &mut NakoSystem, node: &AstNode) {
             ));
             return;
         }
-        sys.error("Invalid Let AST node structure");
+        sys.compile_error(ErrorCode::InvalidAst, node.pos, "『Let』の構文木が不正です");
+    }
+}
+
+/// Create a label to jump to, placed later with `place_label`
+fn new_label(ctx: &mut CompileContext) -> usize {
+    ctx.labels.push(Label::default());
+    ctx.labels.len() - 1
+}
+
+/// Create a label placed at the next code position
+fn new_label_here(sys: &mut NakoSystem, ctx: &mut CompileContext) -> usize {
+    let label = new_label(ctx);
+    place_label(sys, ctx, label);
+    label
+}
+
+/// Place the label at the next code position and patch the jumps waiting for it
+fn place_label(sys: &mut NakoSystem, ctx: &mut CompileContext, label: usize) {
+    let addr = sys.codes.len();
+    let refs = std::mem::take(&mut ctx.labels[label].refs);
+    for index in refs {
+        sys.codes[index].arg1 = addr;
+    }
+    ctx.labels[label].addr = Some(addr);
+}
+
+/// Push a jump code to the label (patched when the label is placed)
+fn push_jump(sys: &mut NakoSystem, ctx: &mut CompileContext, kind: ByteCodeKind, label: usize) {
+    let index = sys.codes.len();
+    let addr = ctx.labels[label].addr;
+    sys.codes.push(ByteCode::new(kind, addr.unwrap_or(0), 0, 0));
+    if addr.is_none() {
+        ctx.labels[label].refs.push(index);
+    }
+}
+
+fn read_if(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    // children: [condition, then_block, else_block]
+    let children = match node.children {
+        Some(ref children) if children.len() == 3 => children,
+        _ => {
+            sys.compile_error(ErrorCode::InvalidAst, node.pos, "『If』の構文木が不正です");
+            return;
+        }
+    };
+    let else_label = new_label(ctx);
+    let end_label = new_label(ctx);
+    read_ast(sys, ctx, &children[0]);
+    push_jump(sys, ctx, ByteCodeKind::JumpIfFalse, else_label);
+    read_ast(sys, ctx, &children[1]);
+    push_jump(sys, ctx, ByteCodeKind::Jump, end_label);
+    place_label(sys, ctx, else_label);
+    read_ast(sys, ctx, &children[2]);
+    place_label(sys, ctx, end_label);
+}
+
+/// Read a loop body in a new loop context with the labels for 抜ける and 続ける
+/// The caller must place both labels
+fn read_loop_body(sys: &mut NakoSystem, ctx: &mut CompileContext, body: &AstNode, loop_ctx: LoopContext) {
+    ctx.loop_stack.push(loop_ctx);
+    read_ast(sys, ctx, body);
+    ctx.loop_stack.pop();
+}
+
+/// Make labels for 抜ける and 続ける
+fn new_loop_context(ctx: &mut CompileContext) -> LoopContext {
+    LoopContext { break_label: new_label(ctx), continue_label: new_label(ctx) }
+}
+
+/// Push a constant value and return its index
+fn push_const(sys: &mut NakoSystem, value: Value) -> usize {
+    let index = sys.const_list.len();
+    sys.const_list.push(value);
+    index
+}
+
+/// Get the variable index to read
+/// Local variable of the compiling function if exists, otherwise global variable
+fn get_var_index(sys: &mut NakoSystem, ctx: &mut CompileContext, name: &str) -> usize {
+    if let Some(ref scope) = ctx.local_scope
+        && let Some(&index) = scope.name_map.get(name) {
+        return index;
+    }
+    sys.var_table.get_name_index_create(name)
+}
+
+/// Get the variable index to write
+/// Variables assigned in a function are local variables of the function
+fn get_store_var_index(sys: &mut NakoSystem, ctx: &mut CompileContext, name: &str) -> usize {
+    let Some(ref scope) = ctx.local_scope else {
+        return sys.var_table.get_name_index_create(name);
+    };
+    if let Some(&index) = scope.name_map.get(name) {
+        return index;
     }
-}
\ No newline at end of file
+    // local variables are stored in the variable table with the function name
+    let func_index = scope.func_index;
+    let func_name = sys.func_table.funcs[func_index].name.clone();
+    let index = sys.var_table.get_name_index_create(&format!("{}:{}", func_name, name));
+    sys.func_table.funcs[func_index].local_vars.push(index);
+    if let Some(ref mut scope) = ctx.local_scope {
+        scope.name_map.insert(name.to_string(), index);
+    }
+    index
+}
+
+/// Collect variable names assigned in the AST (without nested functions)
+fn collect_assigned_names(node: &AstNode, names: &mut Vec<String>) {
+    if let Some(ref children) = node.children {
+        match node.kind {
+            AstKind::FuncDef => return,
+            AstKind::Let | AstKind::For => {
+                if let Some(var_node) = children.first() {
+                    names.push(var_node.value.to_string());
+                }
+            },
+            _ => {},
+        }
+        for child in children {
+            collect_assigned_names(child, names);
+        }
+    }
+}
+
+/// Create a hidden variable used by the compiler (e.g. loop counters)
+fn create_hidden_var(sys: &mut NakoSystem, ctx: &mut CompileContext, name: &str) -> usize {
+    let var_name = format!("${}{}", name, sys.codes.len());
+    get_store_var_index(sys, ctx, &var_name)
+}
+
+/// Copy the variable to a new hidden variable, to put it back with `restore_var`
+fn save_var(sys: &mut NakoSystem, ctx: &mut CompileContext, var: usize, name: &str) -> usize {
+    let saved = create_hidden_var(sys, ctx, name);
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, var, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, saved, 0, 0));
+    saved
+}
+
+/// Put back the variable copied by `save_var`
+fn restore_var(sys: &mut NakoSystem, saved: usize, var: usize) {
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, saved, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, var, 0, 0));
+}
+
+fn read_times(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    // children: [count, body]
+    let children = match node.children {
+        Some(ref children) if children.len() == 2 => children,
+        _ => {
+            sys.compile_error(ErrorCode::InvalidAst, node.pos, "『Times』の構文木が不正です");
+            return;
+        }
+    };
+    // limit = count; counter = 0
+    let limit_var = create_hidden_var(sys, ctx, "limit");
+    let counter_var = create_hidden_var(sys, ctx, "counter");
+    let kaisu_var = get_store_var_index(sys, ctx, "回数");
+    read_ast(sys, ctx, &children[0]);
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, limit_var, 0, 0));
+    // 回数 of the outer loop comes back after this loop
+    let saved_kaisu = save_var(sys, ctx, kaisu_var, "kaisu");
+    let zero = push_const(sys, Value::from_number(0.0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushConst, zero, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, counter_var, 0, 0));
+    // loop top: counter += 1, exit if counter > limit
+    let loop_ctx = new_loop_context(ctx);
+    let loop_top = sys.codes.len();
+    place_label(sys, ctx, loop_ctx.continue_label);
+    sys.codes.push(ByteCode::new(ByteCodeKind::CountUp, counter_var, limit_var, 0));
+    // 回数 = counter
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, counter_var, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, kaisu_var, 0, 0));
+    read_loop_body(sys, ctx, &children[1], loop_ctx);
+    push_jump(sys, ctx, ByteCodeKind::Jump, loop_ctx.continue_label);
+    sys.codes[loop_top].arg3 = sys.codes.len();
+    place_label(sys, ctx, loop_ctx.break_label);
+    restore_var(sys, saved_kaisu, kaisu_var);
+}
+
+fn read_for(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    // children: [var, from, to, step(Nop if omitted), body]
+    let children = match node.children {
+        Some(ref children) if children.len() == 5 => children,
+        _ => {
+            sys.compile_error(ErrorCode::InvalidAst, node.pos, "『For』の構文木が不正です");
+            return;
+        }
+    };
+    let var_name = children[0].value.to_string();
+    let loop_var = get_store_var_index(sys, ctx, &var_name);
+    let to_var = create_hidden_var(sys, ctx, "to");
+    let step_var = create_hidden_var(sys, ctx, "step");
+    // I = from; to = to
+    read_ast(sys, ctx, &children[1]);
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, loop_var, 0, 0));
+    read_ast(sys, ctx, &children[2]);
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, to_var, 0, 0));
+    // step = |step or 1|, negated when the range is descending
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, loop_var, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, to_var, 0, 0));
+    if children[3].kind == AstKind::Nop {
+        let one = push_const(sys, Value::from_number(1.0));
+        sys.codes.push(ByteCode::new(ByteCodeKind::PushConst, one, 0, 0));
+    } else {
+        read_ast(sys, ctx, &children[3]);
+    }
+    sys.codes.push(ByteCode::new_code(ByteCodeKind::ForStep));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, step_var, 0, 0));
+    // loop top: exit if I is out of range
+    let loop_ctx = new_loop_context(ctx);
+    let loop_top = new_label_here(sys, ctx);
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, loop_var, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, to_var, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, step_var, 0, 0));
+    sys.codes.push(ByteCode::new_code(ByteCodeKind::ForCheck));
+    push_jump(sys, ctx, ByteCodeKind::JumpIfFalse, loop_ctx.break_label);
+    read_loop_body(sys, ctx, &children[4], loop_ctx);
+    // I = I + step
+    place_label(sys, ctx, loop_ctx.continue_label);
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, loop_var, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, step_var, 0, 0));
+    sys.codes.push(ByteCode::new_code(ByteCodeKind::Add));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, loop_var, 0, 0));
+    push_jump(sys, ctx, ByteCodeKind::Jump, loop_top);
+    place_label(sys, ctx, loop_ctx.break_label);
+}
+
+fn read_while(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    // children: [condition, body]
+    let children = match node.children {
+        Some(ref children) if children.len() == 2 => children,
+        _ => {
+            sys.compile_error(ErrorCode::InvalidAst, node.pos, "『While』の構文木が不正です");
+            return;
+        }
+    };
+    // check the condition at the bottom, so that each turn needs only one jump
+    let loop_ctx = new_loop_context(ctx);
+    push_jump(sys, ctx, ByteCodeKind::Jump, loop_ctx.continue_label);
+    let body_top = new_label_here(sys, ctx);
+    read_loop_body(sys, ctx, &children[1], loop_ctx);
+    place_label(sys, ctx, loop_ctx.continue_label);
+    read_ast(sys, ctx, &children[0]);
+    push_jump(sys, ctx, ByteCodeKind::JumpIfTrue, body_top);
+    place_label(sys, ctx, loop_ctx.break_label);
+}
+
+fn read_break(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    if ctx.loop_stack.is_empty() {
+        sys.compile_error(ErrorCode::BreakOutsideLoop, node.pos, "『抜ける』が繰り返しの外にあります");
+        return;
+    }
+    if let Some(loop_ctx) = ctx.loop_stack.last().copied() {
+        push_jump(sys, ctx, ByteCodeKind::Jump, loop_ctx.break_label);
+    }
+}
+
+fn read_continue(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    if ctx.loop_stack.is_empty() {
+        sys.compile_error(ErrorCode::ContinueOutsideLoop, node.pos, "『続ける』が繰り返しの外にあります");
+        return;
+    }
+    if let Some(loop_ctx) = ctx.loop_stack.last().copied() {
+        push_jump(sys, ctx, ByteCodeKind::Jump, loop_ctx.continue_label);
+    }
+}
+
+fn read_array(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    read_ast_children(sys, ctx, node);
+    let len = node.children.as_ref().map_or(0, |children| children.len());
+    sys.codes.push(ByteCode::new(ByteCodeKind::MakeArray, len, 0, 0));
+}
+
+fn read_foreach(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    // children: [target, body]
+    let children = match node.children {
+        Some(ref children) if children.len() == 2 => children,
+        _ => {
+            sys.compile_error(ErrorCode::InvalidAst, node.pos, "『Foreach』の構文木が不正です");
+            return;
+        }
+    };
+    let target_var = create_hidden_var(sys, ctx, "target");
+    let index_var = create_hidden_var(sys, ctx, "index");
+    let taisyo_var = get_store_var_index(sys, ctx, "対象");
+    let taisyo_key_var = get_store_var_index(sys, ctx, "対象キー");
+    // target = target; index = 0
+    read_ast(sys, ctx, &children[0]);
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, target_var, 0, 0));
+    let zero = push_const(sys, Value::from_number(0.0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushConst, zero, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, index_var, 0, 0));
+    // 対象 and 対象キー of the outer loop come back after this loop
+    let saved_taisyo = save_var(sys, ctx, taisyo_var, "taisyo");
+    let saved_taisyo_key = save_var(sys, ctx, taisyo_key_var, "taisyo_key");
+    // loop top: push the element and its key, or exit at the end
+    let loop_ctx = new_loop_context(ctx);
+    let loop_top = sys.codes.len();
+    place_label(sys, ctx, loop_ctx.continue_label);
+    sys.codes.push(ByteCode::new(ByteCodeKind::ForeachNext, target_var, index_var, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, taisyo_key_var, 0, 0));
+    sys.codes.push(ByteCode::new(ByteCodeKind::Let, taisyo_var, 0, 0));
+    read_loop_body(sys, ctx, &children[1], loop_ctx);
+    push_jump(sys, ctx, ByteCodeKind::Jump, loop_ctx.continue_label);
+    sys.codes[loop_top].arg3 = sys.codes.len();
+    place_label(sys, ctx, loop_ctx.break_label);
+    restore_var(sys, saved_taisyo, taisyo_var);
+    restore_var(sys, saved_taisyo_key, taisyo_key_var);
+}
+
+fn read_func_def(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    // children: [args, body]
+    let name = lexer::normalize_word(&node.value.to_string());
+    let (func_index, children) = match (sys.func_table.get_name_index(&name), &node.children) {
+        (Some(func_index), Some(children)) if children.len() == 2 => (func_index, children),
+        _ => {
+            sys.compile_error(ErrorCode::InvalidAst, node.pos, "『FuncDef』の構文木が不正です");
+            return;
+        }
+    };
+    if ctx.local_scope.is_some() {
+        sys.compile_error(ErrorCode::NestedFuncDef, node.pos, "関数の中で関数は定義できません");
+        return;
+    }
+    // skip the function body in normal flow
+    let end_label = new_label(ctx);
+    push_jump(sys, ctx, ByteCodeKind::Jump, end_label);
+    sys.func_table.funcs[func_index].addr = sys.codes.len();
+    sys.func_table.funcs[func_index].local_vars.clear();
+    ctx.local_scope = Some(LocalScope { func_index, ..Default::default() });
+    let saved_loop_stack = std::mem::take(&mut ctx.loop_stack);
+    // local variables: arguments, それ, and assigned variables
+    let mut names: Vec<String> = Vec::new();
+    if let Some(ref args) = children[0].children {
+        names.extend(args.iter().map(|arg| arg.value.to_string()));
+    }
+    names.push("それ".to_string());
+    collect_assigned_names(&children[1], &mut names);
+    for name in names {
+        get_store_var_index(sys, ctx, &name);
+    }
+    read_ast(sys, ctx, &children[1]);
+    // return それ at the end of the function
+    let sore_var = get_var_index(sys, ctx, "それ");
+    sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, sore_var, 0, 0));
+    sys.codes.push(ByteCode::new_code(ByteCodeKind::Return));
+    ctx.loop_stack = saved_loop_stack;
+    ctx.local_scope = None;
+    place_label(sys, ctx, end_label);
+}
+
+fn read_call(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    let name = node.value.to_string();
+    let Some(func_index) = sys.func_table.get_name_index(&name) else {
+        sys.compile_error(ErrorCode::FuncNotFound, node.pos, &format!("関数『{}』が見つかりません", name));
+        return;
+    };
+    read_ast_children(sys, ctx, node);
+    let argc = node.children.as_ref().map_or(0, |children| children.len());
+    sys.codes.push(ByteCode::new(ByteCodeKind::Call, func_index, argc, 0));
+}
+
+fn read_return(sys: &mut NakoSystem, ctx: &mut CompileContext, node: &AstNode) {
+    if ctx.local_scope.is_none() {
+        sys.compile_error(ErrorCode::ReturnOutsideFunc, node.pos, "『戻る』が関数の外にあります");
+        return;
+    }
+    match node.children {
+        Some(ref children) if !children.is_empty() => read_ast(sys, ctx, &children[0]),
+        _ => {
+            let sore_var = get_var_index(sys, ctx, "それ");
+            sys.codes.push(ByteCode::new(ByteCodeKind::PushVariable, sore_var, 0, 0));
+        }
+    }
+    sys.codes.push(ByteCode::new_code(ByteCodeKind::Return));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_patches_forward_and_backward_jumps() {
+        let mut sys = NakoSystem::new();
+        let mut ctx = CompileContext::new();
+        let top = new_label_here(&mut sys, &mut ctx);
+        let end = new_label(&mut ctx);
+        push_jump(&mut sys, &mut ctx, ByteCodeKind::JumpIfFalse, end);
+        push_jump(&mut sys, &mut ctx, ByteCodeKind::JumpIfTrue, end);
+        push_jump(&mut sys, &mut ctx, ByteCodeKind::Jump, top);
+        place_label(&mut sys, &mut ctx, end);
+        let targets: Vec<usize> = sys.codes.iter().map(|code| code.arg1).collect();
+        assert_eq!(targets, vec![3, 3, 0]);
+        assert!(ctx.labels[end].refs.is_empty());
+    }
+
+    #[test]
+    fn unplaced_label_is_error() {
+        let mut sys = NakoSystem::new();
+        let mut ctx = CompileContext::new();
+        let label = new_label(&mut ctx);
+        push_jump(&mut sys, &mut ctx, ByteCodeKind::Jump, label);
+        append_bytecodes_with(&mut sys, &mut ctx, &AstNode::new(AstKind::Node));
+        assert_eq!(sys.errors.len(), 1);
+    }
+}
@@ -1,15 +1,24 @@
 /**
  * Nadesiko4 VM code definitions
  */
+use crate::compact::CompactCode;
+use crate::error::{ErrorCode, NakoError, TraceFrame};
+use crate::input::{InputSource, NakoInput, StringInput};
+use crate::lexer;
+use crate::output::{BufferOutput, NakoOutput, OutputSink};
+use crate::source::SourcePos;
+use crate::sys_func;
 use crate::value::Value;
+use crate::verify;
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
 /// VM code type
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ByteCodeKind {
     Nop = 0,
     EOS,
-    Print,
     PushConst,
     PushVariable,
     Add,
@@ -17,6 +26,97 @@ pub enum ByteCodeKind {
     Mul,
     Div,
     Let,
+    Eq,
+    NotEq,
+    Gt,
+    GtEq,
+    Lt,
+    LtEq,
+    Jump,
+    JumpIfFalse,
+    JumpIfTrue,
+    Pop,
+    CountUp,
+    ForStep,
+    ForCheck,
+    MakeArray,
+    ForeachNext,
+    Call,
+    Return,
+}
+impl ByteCodeKind {
+    /// All kinds in the order of their numbers
+    pub const ALL: [ByteCodeKind; 26] = [
+        ByteCodeKind::Nop,
+        ByteCodeKind::EOS,
+        ByteCodeKind::PushConst,
+        ByteCodeKind::PushVariable,
+        ByteCodeKind::Add,
+        ByteCodeKind::Sub,
+        ByteCodeKind::Mul,
+        ByteCodeKind::Div,
+        ByteCodeKind::Let,
+        ByteCodeKind::Eq,
+        ByteCodeKind::NotEq,
+        ByteCodeKind::Gt,
+        ByteCodeKind::GtEq,
+        ByteCodeKind::Lt,
+        ByteCodeKind::LtEq,
+        ByteCodeKind::Jump,
+        ByteCodeKind::JumpIfFalse,
+        ByteCodeKind::JumpIfTrue,
+        ByteCodeKind::Pop,
+        ByteCodeKind::CountUp,
+        ByteCodeKind::ForStep,
+        ByteCodeKind::ForCheck,
+        ByteCodeKind::MakeArray,
+        ByteCodeKind::ForeachNext,
+        ByteCodeKind::Call,
+        ByteCodeKind::Return,
+    ];
+    /// Kind of the number, used when loading compiled files
+    pub fn from_u8(n: u8) -> Option<ByteCodeKind> {
+        ByteCodeKind::ALL.get(n as usize).copied()
+    }
+    /// Name of the kind, such as "PushConst"
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+    /// Kind of the name, such as "PushConst"
+    pub fn from_name(name: &str) -> Option<ByteCodeKind> {
+        ByteCodeKind::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+    /// Meaning of arg1, arg2 and arg3 (unused args are not listed)
+    pub fn operands(&self) -> &'static [Operand] {
+        use Operand::*;
+        match self {
+            ByteCodeKind::EOS => &[Line],
+            ByteCodeKind::PushConst => &[Const],
+            ByteCodeKind::PushVariable | ByteCodeKind::Let => &[Var],
+            ByteCodeKind::Jump | ByteCodeKind::JumpIfFalse | ByteCodeKind::JumpIfTrue => &[Addr],
+            ByteCodeKind::CountUp | ByteCodeKind::ForeachNext => &[Var, Var, Addr],
+            ByteCodeKind::MakeArray => &[Count],
+            ByteCodeKind::Call => &[Func, Count],
+            _ => &[],
+        }
+    }
+}
+
+/// Meaning of an argument of a code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    /// index of const_list
+    Const,
+    /// index of var_table
+    Var,
+    /// index of func_table
+    Func,
+    /// index of codes to jump to
+    Addr,
+    /// number of values
+    Count,
+    /// line number of the source
+    Line,
 }
 
 /// VM code structure
@@ -56,6 +156,11 @@ pub struct NakoVarTable {
     pub vars: Vec<NakoVar>,
     pub name_map: HashMap<String, usize>,
 }
+impl Default for NakoVarTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl NakoVarTable {
     /// Create a new variable table
     pub fn new() -> Self {
@@ -68,6 +173,10 @@ impl NakoVarTable {
     pub fn len(&self) -> usize {
         self.vars.len()
     }
+    /// Check if variable table is empty
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
     pub fn set_by_index(&mut self, index: usize, value: Value) {
         // out of range ... expand
         while index >= self.vars.len() {
@@ -100,12 +209,145 @@ impl NakoVarTable {
             value: Value::None,
         });
         self.name_map.insert(name.to_string(), index);
-        return index;
+        index
     }
     /// Get variable index
     pub fn get_name_index(&self, name: &str) -> Option<usize> {
         self.name_map.get(name).cloned()
     }
+    /// Remove the variables from `len` (such as the ones made by a failed compile)
+    pub fn truncate(&mut self, len: usize) {
+        self.vars.truncate(len);
+        self.name_map.retain(|_, index| *index < len);
+    }
+}
+
+/// System function implemented in Rust (receives the arguments in definition order)
+pub type NakoSysFunc = Arc<dyn Fn(&mut NakoSystem, &[Value]) -> Result<Value, String> + Send + Sync>;
+
+/// Nako Function structure
+#[derive(Clone)]
+pub struct NakoFunc {
+    /// name without okurigana, used to find the function
+    pub name: String,
+    /// name as written at the definition, shown in stack traces and listings
+    pub display_name: String,
+    /// accepted josi of each argument, in definition order
+    pub josi_list: Vec<Vec<String>>,
+    /// start position of the function body in codes
+    pub addr: usize,
+    /// variable indexes of local variables (arguments first)
+    pub local_vars: Vec<usize>,
+    /// body of the system function (None for user-defined functions)
+    pub sys_func: Option<NakoSysFunc>,
+}
+impl fmt::Debug for NakoFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NakoFunc")
+            .field("name", &self.name)
+            .field("display_name", &self.display_name)
+            .field("josi_list", &self.josi_list)
+            .field("addr", &self.addr)
+            .field("local_vars", &self.local_vars)
+            .field("sys_func", &self.sys_func.is_some())
+            .finish()
+    }
+}
+impl NakoFunc {
+    /// Number of arguments
+    pub fn arity(&self) -> usize {
+        self.josi_list.len()
+    }
+}
+/// Nako Function Table
+#[derive(Clone, Debug)]
+pub struct NakoFuncTable {
+    pub funcs: Vec<NakoFunc>,
+    pub name_map: HashMap<String, usize>,
+}
+impl Default for NakoFuncTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl NakoFuncTable {
+    /// Create a new function table
+    pub fn new() -> Self {
+        NakoFuncTable {
+            funcs: Vec::new(),
+            name_map: HashMap::new(),
+        }
+    }
+    /// Length of function table
+    pub fn len(&self) -> usize {
+        self.funcs.len()
+    }
+    /// Check if function table is empty
+    pub fn is_empty(&self) -> bool {
+        self.funcs.is_empty()
+    }
+    /// Register a user-defined function and return its index (replace if already exists)
+    pub fn register(&mut self, name: &str, josi_list: Vec<Vec<String>>) -> usize {
+        self.register_func(NakoFunc {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            josi_list,
+            addr: 0,
+            local_vars: Vec::new(),
+            sys_func: None,
+        })
+    }
+    /// Register a system function and return its index (replace if already exists)
+    pub fn register_sys(&mut self, name: &str, josi_list: &[&[&str]], func: NakoSysFunc) -> usize {
+        let josi_list = josi_list.iter()
+            .map(|josi| josi.iter().map(|j| j.to_string()).collect())
+            .collect();
+        self.register_func(NakoFunc {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            josi_list,
+            addr: 0,
+            local_vars: Vec::new(),
+            sys_func: Some(func),
+        })
+    }
+    /// The name is stored without okurigana, the same as the words of the source
+    fn register_func(&mut self, mut func: NakoFunc) -> usize {
+        func.name = lexer::normalize_word(&func.name);
+        let name = func.name.clone();
+        if let Some(&index) = self.name_map.get(&name) {
+            self.funcs[index] = func;
+            return index;
+        }
+        let index = self.funcs.len();
+        self.funcs.push(func);
+        self.name_map.insert(name, index);
+        index
+    }
+    /// Get function index
+    pub fn get_name_index(&self, name: &str) -> Option<usize> {
+        self.name_map.get(name).cloned()
+    }
+    /// Get function
+    pub fn get_by_index(&self, index: usize) -> Option<&NakoFunc> {
+        self.funcs.get(index)
+    }
+}
+
+/// Call frame of a running function
+#[derive(Clone, Debug)]
+pub struct CallFrame {
+    pub func_index: usize,
+    /// pc to return to
+    pub return_pc: usize,
+    /// stack length when the function was called
+    pub stack_base: usize,
+    /// values of the local variables saved from the caller
+    pub saved_vars: Vec<Value>,
+    /// position of the call site
+    pub call_pos: SourcePos,
+    /// argument values (for stack traces)
+    pub args: Vec<Value>,
 }
 
 /// VM code list structure
@@ -113,35 +355,113 @@ impl NakoVarTable {
 pub struct NakoSystem {
     pub is_debug: bool,
     pub codes: Vec<ByteCode>,
+    /// source position of each code (same length as codes)
+    pub spans: Vec<SourcePos>,
     pub const_list: Vec<Value>,
     pub stack: Vec<Value>,
     pub var_table: NakoVarTable,
-    pub output: String,
-    pub error_msg: String,
+    pub func_table: NakoFuncTable,
+    pub frames: Vec<CallFrame>,
+    /// where the scripts print to (a buffer by default)
+    pub output: OutputSink,
+    /// where the scripts read from (empty by default)
+    pub input: InputSource,
+    /// errors of compiling or running (running stops at the first error)
+    pub errors: Vec<NakoError>,
     pub src_lineno: usize,
+    pub pc: usize,
+    /// codes checked by the earlier runs
+    pub(crate) checked: verify::Checked,
+    /// codes packed by the earlier runs
+    pub(crate) packed: Vec<CompactCode>,
+}
+impl Default for NakoSystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl NakoSystem {
     pub fn new() -> Self {
+        let mut func_table = NakoFuncTable::new();
+        sys_func::register_all(&mut func_table);
         NakoSystem {
             is_debug: false,
             codes: Vec::new(),
+            spans: Vec::new(),
             const_list: Vec::new(),
             var_table: NakoVarTable::new(),
+            func_table,
+            frames: Vec::new(),
             stack: Vec::new(),
-            output: String::new(),
-            error_msg: String::new(),
+            output: OutputSink::new(BufferOutput::new()),
+            input: InputSource::new(StringInput::default()),
+            errors: Vec::new(),
             src_lineno: 0,
+            pc: 0,
+            checked: verify::Checked::default(),
+            packed: Vec::new(),
         }
     }
+    /// Check and pack all codes again at the next run.
+    /// Call this after changing the codes other than appending new ones.
+    pub fn codes_changed(&mut self) {
+        self.checked = verify::Checked::default();
+    }
+    /// Register a host function that scripts can call like a system function.
+    /// `josi_list` gives the accepted josi of each argument, e.g. `&[&["に", "へ"], &["を"]]`.
+    /// The name may have okurigana (「書き出す」 is called as 「書き出して」 or 「書出」).
+    pub fn add_func<F>(&mut self, name: &str, josi_list: &[&[&str]], func: F) -> usize
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.func_table.register_sys(name, josi_list, Arc::new(move |_sys, args| func(args)))
+    }
+    /// Print the text of scripts to the given output
+    pub fn set_output<T: NakoOutput + Send + 'static>(&mut self, output: T) {
+        self.output = OutputSink::new(output);
+    }
+    /// Print to a new buffer and return it to read the text later
+    pub fn capture_output(&mut self) -> BufferOutput {
+        let buffer = BufferOutput::new();
+        self.set_output(buffer.clone());
+        buffer
+    }
+    /// Read the input of scripts from the given source
+    pub fn set_input<T: NakoInput + Send + 'static>(&mut self, input: T) {
+        self.input = InputSource::new(input);
+    }
     pub fn print(&mut self, msg: &str) {
-        self.output.push_str(msg);
+        self.output.write(msg);
     }
     pub fn println(&mut self, msg: &str) {
         self.print(msg);
         self.print("\n");
     }
-    pub fn error(&mut self, msg: &str) {
-        self.error_msg.push_str(msg);
-        self.error_msg.push('\n');
+    /// Record a compile error
+    pub fn compile_error(&mut self, code: ErrorCode, pos: SourcePos, msg: &str) {
+        self.errors.push(NakoError::compile(code, pos, msg));
+    }
+    /// Record a runtime error at the position of the running code
+    pub fn runtime_error(&mut self, code: ErrorCode, msg: &str) {
+        let pos = self.current_pos();
+        let trace = self.stack_trace();
+        self.errors.push(NakoError::runtime(code, pos, msg).with_trace(trace));
+    }
+    /// Stack trace of the running functions (innermost first)
+    pub fn stack_trace(&self) -> Vec<TraceFrame> {
+        self.frames.iter().rev().map(|frame| TraceFrame {
+            func_name: self.func_table.get_by_index(frame.func_index)
+                .map(|func| func.display_name.clone())
+                .unwrap_or_default(),
+            call_pos: frame.call_pos,
+            args: frame.args.clone(),
+        }).collect()
+    }
+    /// Source position of the running code (pc has already been advanced)
+    pub fn current_pos(&self) -> SourcePos {
+        self.pc.checked_sub(1)
+            .and_then(|index| self.spans.get(index))
+            .copied()
+            .unwrap_or(SourcePos::new(self.src_lineno, 0))
     }
 }
@@ -0,0 +1,406 @@
+//! bytecode_file module
+//! Saves and loads compiled programs (.nako4c files).
+//!
+//! Layout (little endian):
+//! - magic `NAKO4BC\0` (8 bytes)
+//! - format version (u16), reserved (u16)
+//! - payload length (u32), CRC-32 of the payload (u32)
+//! - payload: codes, spans, constants, variable names and functions
+
+use std::fmt;
+
+use crate::bytecode::{ByteCode, ByteCodeKind, NakoFunc, NakoSystem, NakoVar, NakoVarTable};
+use crate::lexer;
+use crate::source::SourcePos;
+use crate::value::Value;
+
+/// Magic bytes at the top of a compiled file
+pub const MAGIC: &[u8; 8] = b"NAKO4BC\0";
+/// Version of the format, increased when the layout changes
+pub const FORMAT_VERSION: u16 = 1;
+/// Extension of compiled files
+pub const EXTENSION: &str = "nako4c";
+/// Size of the header before the payload
+const HEADER_LEN: usize = 20;
+
+// tags of constant values
+const TAG_NONE: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_ARRAY: u8 = 4;
+
+/// Error of saving or loading a compiled file
+#[derive(Clone, Debug, PartialEq)]
+pub enum FormatError {
+    /// the data does not start with the magic bytes
+    BadMagic,
+    /// the file was made by another version of the format
+    UnsupportedVersion(u16),
+    /// the payload is broken
+    ChecksumMismatch,
+    /// the data ends in the middle
+    UnexpectedEof,
+    /// the payload has a value that can not be loaded
+    Invalid(String),
+}
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::BadMagic => write!(f, "なでしこ4のコンパイル済みファイルではありません"),
+            FormatError::UnsupportedVersion(v) => write!(f, "対応していない形式のバージョンです(v{})", v),
+            FormatError::ChecksumMismatch => write!(f, "ファイルが壊れています(チェックサムが一致しません)"),
+            FormatError::UnexpectedEof => write!(f, "ファイルが途中で終わっています"),
+            FormatError::Invalid(msg) => write!(f, "ファイルの内容が不正です: {}", msg),
+        }
+    }
+}
+impl std::error::Error for FormatError {}
+
+/// Does the data look like a compiled file?
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Save the compiled program of the system
+pub fn to_bytes(sys: &NakoSystem) -> Result<Vec<u8>, FormatError> {
+    let mut w = Writer::default();
+    // codes and their positions
+    w.len(sys.codes.len())?;
+    for code in &sys.codes {
+        w.u8(code.kind as u8);
+        w.usize(code.arg1)?;
+        w.usize(code.arg2)?;
+        w.usize(code.arg3)?;
+    }
+    for i in 0..sys.codes.len() {
+        let pos = sys.spans.get(i).copied().unwrap_or(SourcePos::zero());
+        w.usize(pos.line)?;
+        w.usize(pos.column)?;
+    }
+    // constants
+    w.len(sys.const_list.len())?;
+    for value in &sys.const_list {
+        w.value(value)?;
+    }
+    // variable names (values are set when running)
+    w.len(sys.var_table.vars.len())?;
+    for var in &sys.var_table.vars {
+        w.str(&var.name)?;
+    }
+    // functions, system functions are saved by name and found again when loading
+    w.len(sys.func_table.funcs.len())?;
+    for func in &sys.func_table.funcs {
+        // the name is found again from the written name when loading
+        w.str(&func.display_name)?;
+        w.u8(func.sys_func.is_some() as u8);
+        w.len(func.josi_list.len())?;
+        for josi in &func.josi_list {
+            w.len(josi.len())?;
+            for j in josi {
+                w.str(j)?;
+            }
+        }
+        w.usize(func.addr)?;
+        w.len(func.local_vars.len())?;
+        for &index in &func.local_vars {
+            w.usize(index)?;
+        }
+    }
+    let payload = w.buf;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    bytes.extend_from_slice(&to_u32(payload.len())?.to_le_bytes());
+    bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Load a compiled program into a new system
+pub fn from_bytes(bytes: &[u8]) -> Result<NakoSystem, FormatError> {
+    from_bytes_with(NakoSystem::new(), bytes)
+}
+
+/// Load a compiled program into the given system, such as one with host functions registered.
+/// The system functions used by the program must be registered in the system.
+pub fn from_bytes_with(mut sys: NakoSystem, bytes: &[u8]) -> Result<NakoSystem, FormatError> {
+    let payload = check_header(bytes)?;
+    let mut r = Reader { buf: payload, pos: 0 };
+    // codes and their positions
+    let code_len = r.len()?;
+    let mut codes = Vec::with_capacity(code_len);
+    for _ in 0..code_len {
+        let n = r.u8()?;
+        let kind = ByteCodeKind::from_u8(n)
+            .ok_or_else(|| FormatError::Invalid(format!("不明な命令番号 {}", n)))?;
+        codes.push(ByteCode::new(kind, r.usize()?, r.usize()?, r.usize()?));
+    }
+    let mut spans = Vec::with_capacity(code_len);
+    for _ in 0..code_len {
+        spans.push(SourcePos::new(r.usize()?, r.usize()?));
+    }
+    // constants
+    let const_len = r.len()?;
+    let mut const_list = Vec::with_capacity(const_len);
+    for _ in 0..const_len {
+        const_list.push(r.value()?);
+    }
+    // variable names, kept at the saved indexes
+    let mut var_table = NakoVarTable::new();
+    for index in 0..r.len()? {
+        let name = r.str()?;
+        if !name.is_empty() {
+            var_table.name_map.insert(name.clone(), index);
+        }
+        var_table.vars.push(NakoVar { name, value: Value::None });
+    }
+    // functions, rebuilt in the saved order because Call codes use their indexes
+    let sys_funcs = std::mem::take(&mut sys.func_table);
+    let func_len = r.len()?;
+    for _ in 0..func_len {
+        let display_name = r.str()?;
+        let name = lexer::normalize_word(&display_name);
+        let is_sys = r.u8()? != 0;
+        let mut josi_list = Vec::new();
+        for _ in 0..r.len()? {
+            let mut josi = Vec::new();
+            for _ in 0..r.len()? {
+                josi.push(r.str()?);
+            }
+            josi_list.push(josi);
+        }
+        let addr = r.usize()?;
+        let mut local_vars = Vec::new();
+        for _ in 0..r.len()? {
+            local_vars.push(r.usize()?);
+        }
+        let sys_func = if is_sys {
+            let func = sys_funcs.get_name_index(&name)
+                .and_then(|index| sys_funcs.get_by_index(index))
+                .and_then(|func| func.sys_func.clone())
+                .ok_or_else(|| FormatError::Invalid(format!("関数『{}』が登録されていません", name)))?;
+            Some(func)
+        } else {
+            None
+        };
+        let index = sys.func_table.len();
+        sys.func_table.funcs.push(NakoFunc { name: name.clone(), display_name, josi_list, addr, local_vars, sys_func });
+        sys.func_table.name_map.insert(name, index);
+    }
+    if !r.is_end() {
+        return Err(FormatError::Invalid("余分なデータがあります".to_string()));
+    }
+    // functions registered to the system but not used by the program can still be called later
+    for func in sys_funcs.funcs {
+        if sys.func_table.get_name_index(&func.name).is_none() {
+            let index = sys.func_table.len();
+            sys.func_table.name_map.insert(func.name.clone(), index);
+            sys.func_table.funcs.push(func);
+        }
+    }
+    sys.codes = codes;
+    sys.spans = spans;
+    sys.var_table = var_table;
+    sys.const_list = const_list;
+    Ok(sys)
+}
+
+/// Check the header and return the payload
+fn check_header(bytes: &[u8]) -> Result<&[u8], FormatError> {
+    if !is_compiled(bytes) {
+        return Err(FormatError::BadMagic);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(FormatError::UnexpectedEof);
+    }
+    let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+    if version != FORMAT_VERSION {
+        return Err(FormatError::UnsupportedVersion(version));
+    }
+    let len = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]) as usize;
+    let checksum = u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]);
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() < len {
+        return Err(FormatError::UnexpectedEof);
+    }
+    let payload = &payload[..len];
+    if crc32(payload) != checksum {
+        return Err(FormatError::ChecksumMismatch);
+    }
+    Ok(payload)
+}
+
+fn to_u32(n: usize) -> Result<u32, FormatError> {
+    u32::try_from(n).map_err(|_| FormatError::Invalid(format!("値が大きすぎます({})", n)))
+}
+
+/// CRC-32 (IEEE 802.3)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Payload writer
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+    fn usize(&mut self, n: usize) -> Result<(), FormatError> {
+        self.buf.extend_from_slice(&to_u32(n)?.to_le_bytes());
+        Ok(())
+    }
+    fn len(&mut self, n: usize) -> Result<(), FormatError> {
+        self.usize(n)
+    }
+    fn str(&mut self, s: &str) -> Result<(), FormatError> {
+        self.len(s.len())?;
+        self.buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+    fn value(&mut self, value: &Value) -> Result<(), FormatError> {
+        match value {
+            Value::None => self.u8(TAG_NONE),
+            Value::Bool(b) => {
+                self.u8(TAG_BOOL);
+                self.u8(*b as u8);
+            },
+            Value::Number(n) => {
+                self.u8(TAG_NUMBER);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            },
+            Value::String(s) => {
+                self.u8(TAG_STRING);
+                self.str(s)?;
+            },
+            Value::Array(items) => {
+                self.u8(TAG_ARRAY);
+                self.len(items.len())?;
+                for item in items {
+                    self.value(item)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Payload reader
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn is_end(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        if self.buf.len() - self.pos < n {
+            return Err(FormatError::UnexpectedEof);
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn usize(&mut self) -> Result<usize, FormatError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+    fn len(&mut self) -> Result<usize, FormatError> {
+        let n = self.usize()?;
+        // every item takes at least one byte, so a larger count is a broken file
+        if n > self.buf.len() - self.pos {
+            return Err(FormatError::UnexpectedEof);
+        }
+        Ok(n)
+    }
+    fn str(&mut self) -> Result<String, FormatError> {
+        let len = self.len()?;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| FormatError::Invalid("文字列がUTF-8ではありません".to_string()))
+    }
+    fn value(&mut self) -> Result<Value, FormatError> {
+        match self.u8()? {
+            TAG_NONE => Ok(Value::None),
+            TAG_BOOL => Ok(Value::Bool(self.u8()? != 0)),
+            TAG_NUMBER => {
+                let b = self.bytes(8)?;
+                let mut n = [0u8; 8];
+                n.copy_from_slice(b);
+                Ok(Value::Number(f64::from_le_bytes(n)))
+            },
+            TAG_STRING => Ok(Value::String(self.str()?)),
+            TAG_ARRAY => {
+                let len = self.len()?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(self.value()?);
+                }
+                Ok(Value::Array(items))
+            },
+            tag => Err(FormatError::Invalid(format!("不明な値の種類 {}", tag))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kind_numbers() {
+        for (i, kind) in ByteCodeKind::ALL.iter().enumerate() {
+            assert_eq!(*kind as usize, i);
+            assert_eq!(ByteCodeKind::from_u8(i as u8), Some(*kind));
+        }
+        assert_eq!(ByteCodeKind::from_u8(ByteCodeKind::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = Value::Array(vec![
+            Value::None, Value::Bool(true), Value::Number(-1.5), Value::from_str("あいう"),
+            Value::Array(vec![]),
+        ]);
+        let mut w = Writer::default();
+        w.value(&value).unwrap();
+        let mut r = Reader { buf: &w.buf, pos: 0 };
+        assert_eq!(r.value().unwrap(), value);
+        assert!(r.is_end());
+    }
+
+    #[test]
+    fn broken_files() {
+        let sys = crate::compile("A=1\nAを表示", &crate::NakoOptions::new()).unwrap();
+        let bytes = to_bytes(&sys).unwrap();
+        assert!(is_compiled(&bytes));
+        assert_eq!(from_bytes(b"A=1").unwrap_err(), FormatError::BadMagic);
+        assert_eq!(from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), FormatError::UnexpectedEof);
+        let mut broken = bytes.clone();
+        *broken.last_mut().unwrap() ^= 0xFF;
+        assert_eq!(from_bytes(&broken).unwrap_err(), FormatError::ChecksumMismatch);
+        let mut newer = bytes.clone();
+        newer[8] = 99;
+        assert_eq!(from_bytes(&newer).unwrap_err(), FormatError::UnsupportedVersion(99));
+    }
+}
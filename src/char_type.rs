@@ -1,21 +1,21 @@
-/// char type module
-/// Character type checking functions 
-/// 各種文字の分類判定を行うヘルパー関数群
+//! char type module
+//! Character type checking functions 
+//! 各種文字の分類判定を行うヘルパー関数群
 
 /// ひらがなかどうかを判定する
 pub fn is_hiragana(c: char) -> bool {
     // ひらがな判定
-    c >= 'ぁ' && c <= 'ゖ'
+    ('ぁ'..='ゖ').contains(&c)
 }
 /// カタカナかどうかを判定する
 pub fn is_katakana(c: char) -> bool {
     // カタカナ判定
-    c >= 'ァ' && c <= 'ヺ'
+    ('ァ'..='ヺ').contains(&c)
 }
 /// 漢字かどうかを判定する
 pub fn is_kanji(c: char) -> bool {
     // 漢字判定（CJK統合漢字と拡張Aの一部）
-    (c >= '一' && c <= '龥') || (c >= '㐀' && c <= '䶵')
+    ('一'..='龥').contains(&c) || ('㐀'..='䶵').contains(&c)
 }
 /// 日本語の文字（ひらがな・カタカナ・漢字）かを判定する
 pub fn is_japanese(c: char) -> bool {
@@ -25,18 +25,35 @@ pub fn is_japanese(c: char) -> bool {
 /// 英字(A-Z, a-z)かどうかを判定する
 pub fn is_alphabet(c: char) -> bool {
     // 英字判定（A-Z, a-z）
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z')
+    c.is_ascii_lowercase() || c.is_ascii_uppercase()
 }
 /// 数字(0-9)かどうかを判定する
 pub fn is_number(c: char) -> bool {
     // 数字判定（0-9）
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 /// 空白文字（スペース・タブ・CR・LF）かを判定する
 pub fn is_whitespace(c: char) -> bool {
     // 空白判定（スペース、タブ、CR、LF）
     c == ' ' || c == '\t' || c == '\r' || c == '\n'
 }
+/// 表示幅を返す（全角文字は2、それ以外は1）
+pub fn char_width(c: char) -> usize {
+    // 全角判定（CJK記号・かな・漢字・全角英数など）
+    let code = c as u32;
+    if (0x1100..=0x115F).contains(&code)
+        || (0x2E80..=0xA4CF).contains(&code)
+        || (0xAC00..=0xD7A3).contains(&code)
+        || (0xF900..=0xFAFF).contains(&code)
+        || (0xFE30..=0xFE4F).contains(&code)
+        || (0xFF00..=0xFF60).contains(&code)
+        || (0xFFE0..=0xFFE6).contains(&code)
+        || (0x20000..=0x3FFFD).contains(&code) {
+        2
+    } else {
+        1
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -90,4 +107,13 @@ mod tests {
         assert!(is_whitespace('\n'));
         assert!(!is_whitespace('a'));
     }
+
+    #[test]
+    fn char_width_checks() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('あ'), 2);
+        assert_eq!(char_width('漢'), 2);
+        assert_eq!(char_width('「'), 2);
+        assert_eq!(char_width('＋'), 2);
+    }
 }
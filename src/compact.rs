@@ -0,0 +1,84 @@
+//! compact module
+//! Compact form of the codes run by the VM loop.
+//!
+//! `ByteCode` keeps three `usize` args (32 bytes a code) so that the compiler, the optimizer
+//! and the tools can edit it freely. Before running, the codes are packed into `CompactCode`
+//! (16 bytes a code), so twice as many codes fit in a cache line.
+//! The packed codes keep their indexes, so jump targets, spans and call frames stay the same.
+
+use crate::bytecode::{ByteCode, ByteCodeKind};
+
+/// Code packed for running: the kind and three u32 args
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct CompactCode {
+    pub kind: ByteCodeKind,
+    pub arg1: u32,
+    pub arg2: u32,
+    pub arg3: u32,
+}
+impl CompactCode {
+    /// Pack the code, or None if an arg does not fit in u32
+    pub fn pack(code: &ByteCode) -> Option<Self> {
+        Some(CompactCode {
+            kind: code.kind,
+            arg1: u32::try_from(code.arg1).ok()?,
+            arg2: u32::try_from(code.arg2).ok()?,
+            arg3: u32::try_from(code.arg3).ok()?,
+        })
+    }
+    /// Unpack the code (for the exec functions shared with the ByteCode loop)
+    pub fn unpack(&self) -> ByteCode {
+        ByteCode::new(self.kind, self.arg1 as usize, self.arg2 as usize, self.arg3 as usize)
+    }
+}
+
+/// Pack all codes, or None if one of them does not fit
+pub fn pack(codes: &[ByteCode]) -> Option<Vec<CompactCode>> {
+    codes.iter().map(CompactCode::pack).collect()
+}
+
+/// Pack the codes from `from` after the ones packed before it, and tell if all codes are packed
+/// (packing stops at a code that does not fit)
+pub fn pack_more(packed: &mut Vec<CompactCode>, codes: &[ByteCode], from: usize) -> bool {
+    packed.truncate(from);
+    if packed.len() == from {
+        packed.extend(codes[from..].iter().map_while(CompactCode::pack));
+    }
+    packed.len() == codes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_size() {
+        assert_eq!(std::mem::size_of::<CompactCode>(), 16);
+        assert!(std::mem::size_of::<ByteCode>() > std::mem::size_of::<CompactCode>());
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let code = ByteCode::new(ByteCodeKind::CountUp, 1, 2, 30);
+        let packed = CompactCode::pack(&code).unwrap();
+        assert_eq!(packed.arg3, 30);
+        assert_eq!(packed.unpack().arg1, 1);
+        // u32に収まらない引数は詰められない
+        assert_eq!(pack(&[code, ByteCode::new(ByteCodeKind::Jump, usize::MAX, 0, 0)]), None);
+    }
+
+    #[test]
+    fn pack_more_keeps_earlier_codes() {
+        let codes = [ByteCode::new(ByteCodeKind::Jump, 1, 0, 0), ByteCode::new(ByteCodeKind::Jump, 2, 0, 0)];
+        let mut packed = Vec::new();
+        assert!(pack_more(&mut packed, &codes[..1], 0));
+        assert!(pack_more(&mut packed, &codes, 1));
+        assert_eq!(packed.iter().map(|code| code.arg1).collect::<Vec<_>>(), vec![1, 2]);
+        // 収まらない命令の後は詰めずに、その前から詰め直せば元に戻る
+        let large = [codes[0], ByteCode::new(ByteCodeKind::Jump, usize::MAX, 0, 0)];
+        assert!(!pack_more(&mut packed, &large, 1));
+        assert!(!pack_more(&mut packed, &codes, 2));
+        assert!(pack_more(&mut packed, &codes, 1));
+    }
+}
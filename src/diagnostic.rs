@@ -0,0 +1,121 @@
+//! diagnostic module
+//! Renders errors with the file name, line:column, the source line and a ^ underline.
+
+use crate::char_type::char_width;
+use crate::error::{NakoError, TraceFrame};
+
+/// Maximum number of frames shown in a stack trace
+const MAX_TRACE_LINES: usize = 10;
+
+/// Render the error (or all errors of `NakoError::Multiple`) for the given source
+pub fn render(err: &NakoError, file_name: &str, source: &str) -> String {
+    let errors = err.errors();
+    let mut out = String::new();
+    for (i, e) in errors.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(&render_one(e, file_name, source));
+    }
+    if errors.len() > 1 {
+        out.push_str(&format!("\n{}件のエラーがあります。\n", errors.len()));
+    }
+    out
+}
+
+/// Render one error
+fn render_one(err: &NakoError, file_name: &str, source: &str) -> String {
+    let info = err.info();
+    // SourcePos starts from 0, but people count from 1
+    let line_no = info.pos.line + 1;
+    let column_no = info.pos.column + 1;
+    let mut out = format!("{}エラー[{}]: {}\n", err.kind_name(), info.code, info.message);
+    let gutter = " ".repeat(line_no.to_string().len());
+    out.push_str(&format!("{}--> {}:{}:{}\n", gutter, file_name, line_no, column_no));
+    if let Some(line) = source.lines().nth(info.pos.line) {
+        let line = line.trim_end_matches('\r');
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line_no, line));
+        out.push_str(&format!("{} | {}\n", gutter, underline(line, info.pos.column, info.len)));
+    }
+    out.push_str(&render_trace(&info.trace, &gutter));
+    out
+}
+
+/// Render the stack trace, showing only the innermost frames when it is long
+fn render_trace(trace: &[TraceFrame], gutter: &str) -> String {
+    if trace.is_empty() {
+        return String::new();
+    }
+    let mut out = format!("{} = 呼び出し履歴(新しい順):\n", gutter);
+    for frame in trace.iter().take(MAX_TRACE_LINES) {
+        out.push_str(&format!("{}     『{}』 引数: {} ({}行目 {}列目から呼び出し)\n",
+            gutter, frame.func_name, frame.args_text(),
+            frame.call_pos.line + 1, frame.call_pos.column + 1));
+    }
+    if trace.len() > MAX_TRACE_LINES {
+        out.push_str(&format!("{}     …他{}件\n", gutter, trace.len() - MAX_TRACE_LINES));
+    }
+    out
+}
+
+/// Make the ^ underline for the span, keeping the alignment of tabs and wide characters
+fn underline(line: &str, column: usize, len: usize) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let column = column.min(chars.len());
+    let mut mark = String::new();
+    for &c in &chars[..column] {
+        if c == '\t' {
+            mark.push('\t');
+        } else {
+            mark.push_str(&" ".repeat(char_width(c)));
+        }
+    }
+    let end = (column + len.max(1)).min(chars.len());
+    let width: usize = chars[column..end].iter().map(|&c| char_width(c)).sum();
+    mark.push_str(&"^".repeat(width.max(1)));
+    mark
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::source::SourcePos;
+
+    #[test]
+    fn render_with_caret() {
+        let err = NakoError::runtime(ErrorCode::DivisionByZero, SourcePos::new(1, 2), "0で割ることはできません");
+        let text = render(&err, "main.nako4", "A=0\n10/Aを表示\n");
+        assert_eq!(text, concat!(
+            "実行時エラー[E0404]: 0で割ることはできません\n",
+            " --> main.nako4:2:3\n",
+            "  |\n",
+            "2 | 10/Aを表示\n",
+            "  |   ^\n",
+        ));
+    }
+
+    #[test]
+    fn underline_wide_chars() {
+        // 全角文字は2桁として数える
+        assert_eq!(underline("もしAが1ならば", 0, 2), "^^^^");
+        assert_eq!(underline("もしAが1ならば", 2, 1), "    ^");
+        assert_eq!(underline("\tB=", 1, 1), "\t^");
+        // 行末を越える場合は行末で止める
+        assert_eq!(underline("abc", 2, 10), "  ^");
+    }
+
+    #[test]
+    fn render_multiple_errors() {
+        let err = NakoError::from_errors(vec![
+            NakoError::parser(ErrorCode::ExpectedExpression, SourcePos::new(0, 1), "『=』の後に式がありません"),
+            NakoError::parser(ErrorCode::MissingTimesCount, SourcePos::new(5, 0), "『回』の前に回数がありません"),
+        ]);
+        let text = render(&err, "a.nako4", "A=\n");
+        assert!(text.contains("1 | A="));
+        // 範囲外の行は抜粋を出さない
+        assert!(text.contains(" --> a.nako4:6:1\n\n"));
+        assert!(text.ends_with("2件のエラーがあります。\n"));
+    }
+}
@@ -0,0 +1,254 @@
+//! error module
+//! Errors reported by the lexer, parser, compiler and VM.
+
+use std::fmt;
+
+use crate::source::SourcePos;
+use crate::value::Value;
+
+/// Stable error code, printed as `E0000` in messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    // lexer
+    UnknownChar,
+    UnclosedString,
+    // parser
+    UnknownToken,
+    ExpectedExpression,
+    MissingBlockEnd,
+    MissingTimesCount,
+    InvalidRepeat,
+    InvalidWhile,
+    InvalidForeach,
+    InvalidFuncDef,
+    InvalidCondition,
+    UnmatchedParen,
+    UnmatchedBracket,
+    MissingOperand,
+    UnusedValue,
+    // compile
+    InvalidAst,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    NestedFuncDef,
+    FuncNotFound,
+    ReturnOutsideFunc,
+    // runtime
+    StackUnderflow,
+    InvalidOperand,
+    TypeMismatch,
+    DivisionByZero,
+    ZeroStep,
+    NotArray,
+    CallDepthExceeded,
+    InvalidReturn,
+    SysFuncFailed,
+    // verify
+    BadOperandIndex,
+    BadJumpTarget,
+    StackDepthUnderflow,
+    StackDepthMismatch,
+    MisplacedReturn,
+    ArityMismatch,
+}
+impl ErrorCode {
+    /// Error code string such as "E0101"
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::UnknownChar => "E0101",
+            ErrorCode::UnclosedString => "E0102",
+            ErrorCode::UnknownToken => "E0201",
+            ErrorCode::ExpectedExpression => "E0202",
+            ErrorCode::MissingBlockEnd => "E0203",
+            ErrorCode::MissingTimesCount => "E0204",
+            ErrorCode::InvalidRepeat => "E0205",
+            ErrorCode::InvalidWhile => "E0206",
+            ErrorCode::InvalidForeach => "E0207",
+            ErrorCode::InvalidFuncDef => "E0208",
+            ErrorCode::InvalidCondition => "E0209",
+            ErrorCode::UnmatchedParen => "E0210",
+            ErrorCode::UnmatchedBracket => "E0211",
+            ErrorCode::MissingOperand => "E0212",
+            ErrorCode::UnusedValue => "E0213",
+            ErrorCode::InvalidAst => "E0301",
+            ErrorCode::BreakOutsideLoop => "E0302",
+            ErrorCode::ContinueOutsideLoop => "E0303",
+            ErrorCode::NestedFuncDef => "E0304",
+            ErrorCode::FuncNotFound => "E0305",
+            ErrorCode::ReturnOutsideFunc => "E0306",
+            ErrorCode::StackUnderflow => "E0401",
+            ErrorCode::InvalidOperand => "E0402",
+            ErrorCode::TypeMismatch => "E0403",
+            ErrorCode::DivisionByZero => "E0404",
+            ErrorCode::ZeroStep => "E0405",
+            ErrorCode::NotArray => "E0406",
+            ErrorCode::CallDepthExceeded => "E0407",
+            ErrorCode::InvalidReturn => "E0408",
+            ErrorCode::SysFuncFailed => "E0409",
+            ErrorCode::BadOperandIndex => "E0501",
+            ErrorCode::BadJumpTarget => "E0502",
+            ErrorCode::StackDepthUnderflow => "E0503",
+            ErrorCode::StackDepthMismatch => "E0504",
+            ErrorCode::MisplacedReturn => "E0505",
+            ErrorCode::ArityMismatch => "E0506",
+        }
+    }
+}
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// One frame of a runtime stack trace
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    pub func_name: String,
+    /// position of the call site
+    pub call_pos: SourcePos,
+    /// argument values at the call
+    pub args: Vec<Value>,
+}
+impl TraceFrame {
+    /// Arguments as text, strings are quoted like 「abc」
+    pub fn args_text(&self) -> String {
+        let args: Vec<String> = self.args.iter().map(|arg| match arg {
+            Value::String(s) => format!("「{}」", s),
+            _ => arg.to_string(),
+        }).collect();
+        args.join(", ")
+    }
+}
+
+/// Details of an error
+#[derive(Clone, Debug, PartialEq)]
+pub struct NakoErrorInfo {
+    pub code: ErrorCode,
+    pub pos: SourcePos,
+    /// length of the span in characters
+    pub len: usize,
+    /// message in Japanese
+    pub message: String,
+    /// called functions at the runtime error (innermost first)
+    pub trace: Vec<TraceFrame>,
+}
+
+/// Error of each stage
+#[derive(Clone, Debug, PartialEq)]
+pub enum NakoError {
+    Lexer(NakoErrorInfo),
+    Parser(NakoErrorInfo),
+    Compile(NakoErrorInfo),
+    Runtime(NakoErrorInfo),
+    /// bytecode rejected before running
+    Verify(NakoErrorInfo),
+    /// several errors found in one pass (never empty)
+    Multiple(Vec<NakoError>),
+}
+impl NakoError {
+    pub fn lexer(code: ErrorCode, pos: SourcePos, message: &str) -> Self {
+        NakoError::Lexer(NakoErrorInfo { code, pos, len: 1, message: message.to_string(), trace: Vec::new() })
+    }
+    pub fn parser(code: ErrorCode, pos: SourcePos, message: &str) -> Self {
+        NakoError::Parser(NakoErrorInfo { code, pos, len: 1, message: message.to_string(), trace: Vec::new() })
+    }
+    pub fn compile(code: ErrorCode, pos: SourcePos, message: &str) -> Self {
+        NakoError::Compile(NakoErrorInfo { code, pos, len: 1, message: message.to_string(), trace: Vec::new() })
+    }
+    pub fn runtime(code: ErrorCode, pos: SourcePos, message: &str) -> Self {
+        NakoError::Runtime(NakoErrorInfo { code, pos, len: 1, message: message.to_string(), trace: Vec::new() })
+    }
+    pub fn verify(code: ErrorCode, pos: SourcePos, message: &str) -> Self {
+        NakoError::Verify(NakoErrorInfo { code, pos, len: 1, message: message.to_string(), trace: Vec::new() })
+    }
+    /// Set the length of the span
+    pub fn with_len(mut self, len: usize) -> Self {
+        match self {
+            NakoError::Lexer(ref mut info)
+            | NakoError::Parser(ref mut info)
+            | NakoError::Compile(ref mut info)
+            | NakoError::Runtime(ref mut info)
+            | NakoError::Verify(ref mut info) => info.len = len,
+            NakoError::Multiple(_) => {},
+        }
+        self
+    }
+    /// Set the stack trace
+    pub fn with_trace(mut self, trace: Vec<TraceFrame>) -> Self {
+        match self {
+            NakoError::Lexer(ref mut info)
+            | NakoError::Parser(ref mut info)
+            | NakoError::Compile(ref mut info)
+            | NakoError::Runtime(ref mut info)
+            | NakoError::Verify(ref mut info) => info.trace = trace,
+            NakoError::Multiple(_) => {},
+        }
+        self
+    }
+    /// Make one error from the list of errors (the list must not be empty)
+    pub fn from_errors(mut errors: Vec<NakoError>) -> Self {
+        if errors.len() == 1 {
+            return errors.remove(0);
+        }
+        NakoError::Multiple(errors)
+    }
+    /// All errors as a list
+    pub fn errors(&self) -> &[NakoError] {
+        match self {
+            NakoError::Multiple(errors) => errors,
+            _ => std::slice::from_ref(self),
+        }
+    }
+    /// Details of the error (the first one for Multiple)
+    pub fn info(&self) -> &NakoErrorInfo {
+        match self {
+            NakoError::Lexer(info)
+            | NakoError::Parser(info)
+            | NakoError::Compile(info)
+            | NakoError::Runtime(info)
+            | NakoError::Verify(info) => info,
+            NakoError::Multiple(errors) => errors[0].info(),
+        }
+    }
+    pub fn code(&self) -> ErrorCode {
+        self.info().code
+    }
+    pub fn pos(&self) -> SourcePos {
+        self.info().pos
+    }
+    pub fn message(&self) -> &str {
+        &self.info().message
+    }
+    /// Stack trace of the runtime error (innermost first)
+    pub fn trace(&self) -> &[TraceFrame] {
+        &self.info().trace
+    }
+    /// Name of the stage in Japanese
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            NakoError::Lexer(_) => "字句解析",
+            NakoError::Parser(_) => "文法",
+            NakoError::Compile(_) => "コンパイル",
+            NakoError::Runtime(_) => "実行時",
+            NakoError::Verify(_) => "検証",
+            NakoError::Multiple(errors) => errors[0].kind_name(),
+        }
+    }
+}
+impl fmt::Display for NakoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let NakoError::Multiple(errors) = self {
+            for (i, err) in errors.iter().enumerate() {
+                if i > 0 {
+                    writeln!(f)?;
+                }
+                write!(f, "{}", err)?;
+            }
+            return Ok(());
+        }
+        let info = self.info();
+        // line numbers start from 0 in SourcePos
+        write!(f, "[{}エラー][{}] {}行目: {}", self.kind_name(), info.code, info.pos.line + 1, info.message)
+    }
+}
+impl std::error::Error for NakoError {}
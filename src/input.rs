@@ -0,0 +1,97 @@
+//! input module
+//! Sources of the text read by scripts (stdin or canned text for tests and embedders).
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Source of the text read by scripts
+pub trait NakoInput {
+    /// Read one line without the newline, or None at the end of the input
+    fn read_line(&mut self) -> Option<String>;
+    /// Read all of the rest of the input
+    fn read_all(&mut self) -> String;
+}
+
+/// Shared handle of the input, so that clones of a NakoSystem read from the same place.
+/// It is Send, like the output sink.
+#[derive(Clone)]
+pub struct InputSource(Arc<Mutex<dyn NakoInput + Send>>);
+impl InputSource {
+    pub fn new<T: NakoInput + Send + 'static>(input: T) -> Self {
+        InputSource(Arc::new(Mutex::new(input)))
+    }
+    pub fn read_line(&self) -> Option<String> {
+        self.lock().read_line()
+    }
+    pub fn read_all(&self) -> String {
+        self.lock().read_all()
+    }
+    /// Lock the input, even if a thread panicked while holding it
+    fn lock(&self) -> MutexGuard<'_, dyn NakoInput + Send + 'static> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+impl fmt::Debug for InputSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InputSource")
+    }
+}
+
+/// Read from stdin
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdinInput;
+impl NakoInput for StdinInput {
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(trim_newline(&line).to_string()),
+        }
+    }
+    fn read_all(&mut self) -> String {
+        let mut text = String::new();
+        let _ = io::stdin().lock().read_to_string(&mut text);
+        text
+    }
+}
+
+/// Read from the given text (empty by default)
+#[derive(Clone, Debug, Default)]
+pub struct StringInput {
+    lines: VecDeque<String>,
+}
+impl StringInput {
+    pub fn new(text: &str) -> Self {
+        StringInput { lines: text.lines().map(|line| trim_newline(line).to_string()).collect() }
+    }
+}
+impl NakoInput for StringInput {
+    fn read_line(&mut self) -> Option<String> {
+        self.lines.pop_front()
+    }
+    fn read_all(&mut self) -> String {
+        self.lines.drain(..).map(|line| line + "\n").collect()
+    }
+}
+
+/// Remove the newline at the end ("\n" or "\r\n")
+fn trim_newline(line: &str) -> &str {
+    line.trim_end_matches(['\r', '\n'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_input() {
+        let input = InputSource::new(StringInput::new("abc\r\n123\nxyz\n"));
+        assert_eq!(input.read_line(), Some("abc".to_string()));
+        assert_eq!(input.clone().read_line(), Some("123".to_string()));
+        assert_eq!(input.read_all(), "xyz\n");
+        assert_eq!(input.read_line(), None);
+        assert_eq!(input.read_all(), "");
+    }
+}
@@ -1,14 +1,23 @@
+use crate::error::{ErrorCode, NakoError};
 use crate::source::Source;
 
 /// lexer module
 use crate::token::{Token, TokenKind};
 use crate::char_type::{is_alphabet, is_hiragana, is_kanji, is_katakana};
 
-static JOSI2: [&str; 5] = ["から", "まで", "から", "には", "とは"];
+static JOSI3: [&str; 1] = ["ならば"];
+static JOSI2: [&str; 7] = ["から", "まで", "なら", "には", "とは", "ずつ", "して"];
 static JOSI1: [char; 8] = ['と', 'は', 'が', 'を', 'に', 'で', 'へ', 'の'];
 
+/// 予約語 --- 単語の区切りや助詞の判定より先に確認する
+static RESERVED_WORDS: [(&str, TokenKind); 3] = [
+    ("もし", TokenKind::If),
+    ("違えば", TokenKind::Else),
+    ("ここまで", TokenKind::BlockEnd),
+];
+
 // Lexer implementation
-pub fn lex(src: &mut Source) -> Vec<Token> {
+pub fn lex(src: &mut Source) -> Result<Vec<Token>, NakoError> {
     let mut tokens: Vec<Token> = Vec::new();
     while let Some(ch) = src.peek() {
         // println!("ch: {:?}", ch);
@@ -17,8 +26,8 @@ pub fn lex(src: &mut Source) -> Vec<Token> {
             '#' => lex_comment(src, &mut tokens),
             '0'..='9' => lex_number(src, &mut tokens),
             'a'..='z' | 'A'..='Z' | '_' => lex_alphabetic_word(src, &mut tokens),
-            '"' => lex_string(src, &mut tokens, '"', '"'),
-            '「' => lex_string(src, &mut tokens, '「', '」'),
+            '"' => lex_string(src, &mut tokens, '"', '"')?,
+            '「' => lex_string(src, &mut tokens, '「', '」')?,
             '。' | ';' | '\n' => lex_eos(src, &mut tokens, ch),
             '+' | '＋' => tokens.push(get_operator(src, '+', TokenKind::Plus)),
             '-' | '−' => tokens.push(get_operator(src, '-', TokenKind::Minus)),
@@ -26,13 +35,21 @@ pub fn lex(src: &mut Source) -> Vec<Token> {
             '/' | '÷' => tokens.push(get_operator(src, '/', TokenKind::Div)),
             '（' | '(' => tokens.push(get_operator(src, '(', TokenKind::ParenL)),
             '）' | ')' => tokens.push(get_operator(src, ')', TokenKind::ParenR)),
-            '＝' | '=' => tokens.push(get_operator(src, '=', TokenKind::Eq)),
-            _ if is_japanese_word(ch) => lex_japanese_word(src, &mut tokens),
-            _ => lex_unknown(src, &mut tokens, ch),
+            '［' | '[' => tokens.push(get_operator(src, '[', TokenKind::BracketL)),
+            '］' | ']' => tokens.push(get_operator(src, ']', TokenKind::BracketR)),
+            '，' | ',' => tokens.push(get_operator(src, ',', TokenKind::Comma)),
+            '●' => tokens.push(get_operator(src, '●', TokenKind::FuncDef)),
+            '＝' | '=' | '>' | '＞' | '<' | '＜' |
+            '≧' | '≥' | '≦' | '≤' | '≠' => tokens.push(get_compare_operator(src, ch)),
+            '!' | '！' if matches!(src.peek_n(2).chars().nth(1), Some('=' | '＝')) => {
+                tokens.push(get_compare_operator(src, ch));
+            },
+            _ if is_japanese_word(ch) => lex_japanese_word(src, &mut tokens)?,
+            _ => return Err(unknown_char_error(src, ch)),
         }
     }
     
-    tokens
+    Ok(tokens)
 }
 
 fn is_japanese_word(c: char) -> bool {
@@ -49,7 +66,7 @@ fn lex_number(src: &mut Source, tokens: &mut Vec<Token>) {
     let pos = src.get_position();
     let mut number = String::new();
     while let Some(c) = src.peek() {
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             number.push(c);
             src.next();
         } else {
@@ -78,10 +95,11 @@ fn lex_alphabetic_word(src: &mut Source, tokens: &mut Vec<Token>) {
     tokens.push(tok);
 }
 
-fn lex_string(src: &mut Source, tokens: &mut Vec<Token>, bos: char, eos: char) {
-    let mut tok = get_string_literal(src, bos, eos);
+fn lex_string(src: &mut Source, tokens: &mut Vec<Token>, bos: char, eos: char) -> Result<(), NakoError> {
+    let mut tok = get_string_literal(src, bos, eos)?;
     tok.josi = get_josi(src);
     tokens.push(tok);
+    Ok(())
 }
 
 fn lex_eos(src: &mut Source, tokens: &mut Vec<Token>, ch: char) {
@@ -91,23 +109,56 @@ fn lex_eos(src: &mut Source, tokens: &mut Vec<Token>, ch: char) {
     tokens.push(Token::new(TokenKind::EOS, Some(symbol), pos));
 }
 
-fn lex_japanese_word(src: &mut Source, tokens: &mut Vec<Token>) {
+fn lex_japanese_word(src: &mut Source, tokens: &mut Vec<Token>) -> Result<(), NakoError> {
+    // 予約語
+    for (word, kind) in RESERVED_WORDS.iter() {
+        if src.test_string(word) {
+            let pos = src.get_position();
+            src.next_n(word.chars().count());
+            tokens.push(Token::new(*kind, Some(word.to_string()), pos));
+            return Ok(());
+        }
+    }
     let mut tok = match get_word(src) {
         Some(t) => t,
-        None => return,
+        None => {
+            // 単語にならない文字(単独の助詞など)はエラー
+            let ch = src.peek().unwrap_or('\0');
+            return Err(unknown_char_error(src, ch));
+        },
     };
-    if tok.value_is("表示") {
-        tok.kind = TokenKind::Print;
+    if tok.value_is("回") {
+        tok.kind = TokenKind::Times;
+    } else if tok.value_is("繰返") {
+        tok.kind = TokenKind::Repeat;
+    } else if tok.value_is("間") {
+        tok.kind = TokenKind::While;
+    } else if tok.value_is("抜") {
+        tok.kind = TokenKind::Break;
+    } else if tok.value_is("続") {
+        tok.kind = TokenKind::Continue;
+    } else if tok.value_is("反復") {
+        tok.kind = TokenKind::Foreach;
+    } else if tok.value_is("戻") {
+        tok.kind = TokenKind::Return;
     }
     tokens.push(tok);
+    Ok(())
 }
 
-fn lex_unknown(src: &mut Source, tokens: &mut Vec<Token>, ch: char) {
-    let pos = src.get_position();
-    println!("未知の文字: {}", ch);
-    let symbol = ch.to_string();
-    src.next();
-    tokens.push(Token::new(TokenKind::Nop, Some(symbol), pos));
+/// 単語を字句解析と同じ形(送り仮名を省略した形)にする --- 「書き出す」は「書出」
+/// 一つの単語として読めない名前はそのまま返す
+pub fn normalize_word(name: &str) -> String {
+    let mut src = Source::new(name);
+    match get_word(&mut src) {
+        Some(tok) if tok.josi.is_none() && src.is_eof() => tok.value.unwrap_or_default(),
+        _ => name.to_string(),
+    }
+}
+
+fn unknown_char_error(src: &Source, ch: char) -> NakoError {
+    NakoError::lexer(ErrorCode::UnknownChar, src.get_position(),
+        &format!("未知の文字『{}』があります", ch))
 }
 
 fn get_operator(src: &mut Source, op_char: char, kind: TokenKind) -> Token {
@@ -115,24 +166,50 @@ fn get_operator(src: &mut Source, op_char: char, kind: TokenKind) -> Token {
     src.next(); // consume operator
     let mut op = Token::new(kind, Some(op_char.to_string()), pos);
     // 閉じ括弧の時は助詞があるかも
-    if op_char == ')' {
+    if op_char == ')' || op_char == ']' {
         op.josi = get_josi(src);
     }
     op
 }
 
+/// 比較演算子を読む --- 2文字の演算子(>=, <=, ==, !=, <>)も判定する
+fn get_compare_operator(src: &mut Source, op_char: char) -> Token {
+    let pos = src.get_position();
+    src.next(); // consume operator
+    let next_char = src.peek().unwrap_or('\0');
+    let is_eq = next_char == '=' || next_char == '＝';
+    let (kind, op, len2) = match op_char {
+        '>' | '＞' if is_eq => (TokenKind::GtEq, ">=", true),
+        '<' | '＜' if is_eq => (TokenKind::LtEq, "<=", true),
+        '<' | '＜' if next_char == '>' || next_char == '＞' => (TokenKind::NotEq, "!=", true),
+        '=' | '＝' if is_eq => (TokenKind::EqEq, "==", true),
+        '!' | '！' => (TokenKind::NotEq, "!=", true),
+        '>' | '＞' => (TokenKind::Gt, ">", false),
+        '<' | '＜' => (TokenKind::Lt, "<", false),
+        '≧' | '≥' => (TokenKind::GtEq, ">=", false),
+        '≦' | '≤' => (TokenKind::LtEq, "<=", false),
+        '≠' => (TokenKind::NotEq, "!=", false),
+        _ => (TokenKind::Eq, "=", false),
+    };
+    if len2 {
+        src.next(); // consume second char
+    }
+    Token::new(kind, Some(op.to_string()), pos)
+}
+
 /// Helper function to extract string literals
-fn get_string_literal(src: &mut Source, bos: char, eos: char) -> Token {
+fn get_string_literal(src: &mut Source, bos: char, eos: char) -> Result<Token, NakoError> {
     let pos = src.get_position();
+    let mut closed = false;
     let mut literal = String::new();
-    if let Some(c) = src.peek() {
-        if c == bos {
-            src.next(); // consume opening quote
-        }
+    if let Some(c) = src.peek()
+        && c == bos {
+        src.next(); // consume opening quote
     }
 
     while let Some(next_ch) = src.next() {
         if next_ch == eos {
+            closed = true;
             break;
         }
         if next_ch == '\\' {
@@ -150,7 +227,11 @@ fn get_string_literal(src: &mut Source, bos: char, eos: char) -> Token {
         }
         literal.push(next_ch);
     }
-    Token::new(TokenKind::Str, Some(literal), pos)
+    if !closed {
+        return Err(NakoError::lexer(ErrorCode::UnclosedString, pos,
+            &format!("文字列の終わり『{}』がありません", eos)).with_len(literal.chars().count() + 1));
+    }
+    Ok(Token::new(TokenKind::Str, Some(literal), pos))
 }
 
 /// Check and extract josi (particles)
@@ -164,6 +245,12 @@ fn get_josi(src: &mut Source) -> Option<String> {
 
 /// 助詞チェック - 助詞だったらその長さを返す
 fn is_josi(src: &mut Source) -> usize {
+    // 3char
+    for josi in JOSI3.iter() {
+        if src.test_string(josi) {
+            return 3;
+        }
+    }
     // 2char
     for josi in JOSI2.iter() {
         if src.test_string(josi) {
@@ -171,10 +258,9 @@ fn is_josi(src: &mut Source) -> usize {
         }
     }
     // 1char
-    if let Some(c) = src.peek() {
-        if JOSI1.contains(&c) {
-            return 1;
-        }
+    if let Some(c) = src.peek()
+        && JOSI1.contains(&c) {
+        return 1;
     }
     0
 }
@@ -192,12 +278,14 @@ fn get_word(src: &mut Source) -> Option<Token> {
 fn get_word_kanji(src: &mut Source) -> Option<Token> {
     let pos = src.get_position();
     let mut word = String::new();
+    let mut raw = String::new();
     // 漢字(送り仮名|漢字)+ + 助詞
     while src.has_more() {
-        // 漢字
+        // 漢字 (「対象キー」のように続くカタカナも含める)
         while let Some(c) = src.peek() {
-            if !is_kanji(c) { break; }
+            if !is_kanji(c) && !is_katakana(c) && c != 'ー' { break; }
             word.push(c);
+            raw.push(c);
             src.next();
         }
         // 送り仮名
@@ -207,6 +295,7 @@ fn get_word_kanji(src: &mut Source) -> Option<Token> {
                 break;
             }
             // 送り仮名は省略 --- word.push(c);
+            raw.push(c);
             src.next();
         }
         if src.is_kanji() { continue; }
@@ -219,11 +308,17 @@ fn get_word_kanji(src: &mut Source) -> Option<Token> {
             pos,
         );
         tok.josi = get_josi(src);
+        tok.raw = raw_if_differs(raw, &tok);
         return Some(tok);
     }
     None
 }
 
+/// 書かれたままの単語(送り仮名つき)を、省略した形と違うときだけ残す
+fn raw_if_differs(raw: String, tok: &Token) -> Option<String> {
+    if tok.value_is(&raw) { None } else { Some(raw) }
+}
+
 fn get_word_hiragana(src: &mut Source) -> Option<Token> {
     let pos = src.get_position();
     let mut word = String::new();
@@ -255,10 +350,13 @@ fn get_word_katakana(src: &mut Source) -> Option<Token> {
     let pos = src.get_position();
     let mut word = String::new();
     // カタカナ + 送り仮名 + 助詞
+    let mut raw = String::new();
     // カタカナ
     while let Some(c) = src.peek() {
-        if !is_katakana(c) { break; }
+        // 長音記号(ー)もカタカナ語の一部とする
+        if !is_katakana(c) && c != 'ー' { break; }
         word.push(c);
+        raw.push(c);
         src.next();
     }
     // 送り仮名
@@ -266,6 +364,7 @@ fn get_word_katakana(src: &mut Source) -> Option<Token> {
         if !is_hiragana(c) { break; }
         if is_josi(src) > 0 { break; }
         // 送り仮名は省略 --- word.push(c);
+        raw.push(c);
         src.next();
     }
     if !word.is_empty() {
@@ -275,6 +374,7 @@ fn get_word_katakana(src: &mut Source) -> Option<Token> {
             pos,
         );
         tok.josi = get_josi(src);
+        tok.raw = raw_if_differs(raw, &tok);
         return Some(tok);
     }
     None
@@ -299,6 +399,7 @@ mod tests {
         assert_word("価格の", "価格", Some("の"));
         assert_word("価格から税率を", "価格", Some("から"));
         assert_word("逃げ切るは", "逃切", Some("は"));
+        assert_word("対象キーを", "対象キー", Some("を"));
     }
 
     #[test]
@@ -306,6 +407,27 @@ mod tests {
         assert_word("表示する", "表示", None);
     }
 
+    #[test]
+    fn word_keeps_raw_text() {
+        // 送り仮名を省略したときは書かれたままの単語も残す
+        let tok = get_word(&mut Source::new("割り算するとは")).unwrap();
+        assert_eq!(tok.value.as_deref(), Some("割算"));
+        assert_eq!(tok.raw.as_deref(), Some("割り算する"));
+        let tok = get_word(&mut Source::new("価格を")).unwrap();
+        assert_eq!(tok.raw, None);
+    }
+
+    #[test]
+    fn normalize_word_drops_okurigana() {
+        assert_eq!(normalize_word("書き出す"), "書出");
+        assert_eq!(normalize_word("尋ねる"), "尋");
+        assert_eq!(normalize_word("書出"), "書出");
+        assert_eq!(normalize_word("ソートする"), "ソート");
+        // 一つの単語として読めない名前はそのまま
+        assert_eq!(normalize_word("double"), "double");
+        assert_eq!(normalize_word("猫を"), "猫を");
+    }
+
     #[test]
     fn hiragana_word_with_josi() {
         assert_word("あいに行く", "あい", Some("に"));
@@ -316,6 +438,11 @@ mod tests {
         assert_word("カタカナで", "カタカナ", Some("で"));
     }
 
+    #[test]
+    fn katakana_word_with_long_vowel() {
+        assert_word("キーを", "キー", Some("を"));
+    }
+
     #[test]
     fn non_japanese_input_returns_none() {
         let mut src = Source::new("abc");
@@ -324,11 +451,107 @@ mod tests {
 
     fn assert_lex(input: &str, expected_kinds: Vec<TokenKind>) {
         let mut src = Source::new(input);
-        let tokens = lex(&mut src);
+        let tokens = lex(&mut src).unwrap();
         let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind).collect();
         assert_eq!(kinds, expected_kinds);
     }
 
+    #[test]
+    fn josi_naraba() {
+        assert_word("値ならば", "値", Some("ならば"));
+        assert_word("値なら", "値", Some("なら"));
+    }
+
+    #[test]
+    fn test_lex_if() {
+        assert_lex("もしAが5ならば\n違えば\nここまで", vec![
+            TokenKind::If,
+            TokenKind::Word,
+            TokenKind::Number,
+            TokenKind::EOS,
+            TokenKind::Else,
+            TokenKind::EOS,
+            TokenKind::BlockEnd,
+        ]);
+    }
+
+    #[test]
+    fn test_lex_compare() {
+        assert_lex("1>2 1>=2 1<2 1<=2 1==2 1!=2 1<>2", vec![
+            TokenKind::Number, TokenKind::Gt, TokenKind::Number,
+            TokenKind::Number, TokenKind::GtEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::Lt, TokenKind::Number,
+            TokenKind::Number, TokenKind::LtEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::EqEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::NotEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::NotEq, TokenKind::Number,
+        ]);
+        assert_lex("1＞2 1≧2 1＜2 1≦2 1≠2 1＝2", vec![
+            TokenKind::Number, TokenKind::Gt, TokenKind::Number,
+            TokenKind::Number, TokenKind::GtEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::Lt, TokenKind::Number,
+            TokenKind::Number, TokenKind::LtEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::NotEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::Eq, TokenKind::Number,
+        ]);
+        // 半角と全角が混ざっていても「!=」として読む
+        assert_lex("1！=2 1!＝2 1！＝2", vec![
+            TokenKind::Number, TokenKind::NotEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::NotEq, TokenKind::Number,
+            TokenKind::Number, TokenKind::NotEq, TokenKind::Number,
+        ]);
+    }
+
+    #[test]
+    fn test_lex_times() {
+        assert_lex("3回\n回数を表示", vec![
+            TokenKind::Number,
+            TokenKind::Times,
+            TokenKind::EOS,
+            TokenKind::Word,
+            TokenKind::Word,
+        ]);
+    }
+
+    #[test]
+    fn test_lex_repeat() {
+        let mut src = Source::new("Iを1から10まで2ずつ繰り返す");
+        let tokens = lex(&mut src).unwrap();
+        let josi: Vec<Option<&str>> = tokens.iter().map(|t| t.josi.as_deref()).collect();
+        assert_eq!(josi, vec![Some("を"), Some("から"), Some("まで"), Some("ずつ"), None]);
+        assert_eq!(tokens[4].kind, TokenKind::Repeat);
+    }
+
+    #[test]
+    fn test_lex_while() {
+        assert_lex("A<5の間\n抜ける\n続ける", vec![
+            TokenKind::Word,
+            TokenKind::Lt,
+            TokenKind::Number,
+            TokenKind::While,
+            TokenKind::EOS,
+            TokenKind::Break,
+            TokenKind::EOS,
+            TokenKind::Continue,
+        ]);
+    }
+
+    #[test]
+    fn test_lex_foreach() {
+        let mut src = Source::new("[1,2]を反復");
+        let tokens = lex(&mut src).unwrap();
+        let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::BracketL,
+            TokenKind::Number,
+            TokenKind::Comma,
+            TokenKind::Number,
+            TokenKind::BracketR,
+            TokenKind::Foreach,
+        ]);
+        assert_eq!(tokens[4].josi.as_deref(), Some("を"));
+    }
+
     #[test]
     fn test_lex_number() {
         assert_lex("1と12を", vec![
@@ -336,6 +559,13 @@ mod tests {
             TokenKind::Number,
         ]);
     }
-}
-
 
+    #[test]
+    fn test_lex_errors() {
+        let err = lex(&mut Source::new("A=1\n「abc")).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::UnclosedString);
+        assert_eq!(err.pos().line, 1);
+        let err = lex(&mut Source::new("A=1{")).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::UnknownChar);
+    }
+}
@@ -1,7 +1,13 @@
 pub mod lexer;
 pub mod parser;
 pub mod bytecode;
+pub mod bytecode_file;
+pub mod compact;
+pub mod asm;
+pub mod verify;
+pub mod optimize;
 pub mod vm;
+pub mod regvm;
 pub mod token;
 pub mod ast;
 pub mod ast_to_bytecode;
@@ -9,24 +15,43 @@ pub mod error;
 pub mod source;
 pub mod value;
 pub mod char_type;
+pub mod sys_func;
+pub mod diagnostic;
+pub mod output;
+pub mod input;
+pub mod repl;
 
 use crate::bytecode::NakoSystem;
+use crate::error::NakoError;
 use crate::token::TokenKind;
 
 /// Options for Nako4 compiler and VM
+#[derive(Clone, Debug)]
 pub struct NakoOptions {
+    /// print the tokens, the AST, the codes and the execution to stderr (-D)
     pub is_debug: bool,
+    /// optimize the codes before running (-O)
+    pub optimize: bool,
+    /// run on the register VM instead of the stack VM (-R)
+    pub register_vm: bool,
+}
+impl Default for NakoOptions {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl NakoOptions {
     pub fn new() -> Self {
         NakoOptions {
             is_debug: false,
+            optimize: false,
+            register_vm: false,
         }
     }
 }
 
 pub fn hello() -> String {
-    return "hello".to_string();
+    "hello".to_string()
 }
 
 /// Returns the current version of the nadesiko4 crate.
@@ -35,60 +60,117 @@ pub fn version() -> &'static str {
 }
 
 /// Compile the given source code into VM code.
-pub fn compile(source: &str, options: &NakoOptions) -> NakoSystem {
+pub fn compile(source: &str, options: &NakoOptions) -> Result<NakoSystem, NakoError> {
+    compile_with_system(NakoSystem::new(), source, options)
+}
+
+/// Compile the given source code into VM code using the given system.
+/// Host functions registered with `NakoSystem::add_func` can be called from the source.
+pub fn compile_with_system(mut sys: NakoSystem, source: &str, options: &NakoOptions) -> Result<NakoSystem, NakoError> {
+    compile_into(&mut sys, source, options)?;
+    Ok(sys)
+}
+
+/// Compile more source code into the existing system.
+/// The new codes are appended after the current ones, and the variables, constants and
+/// functions of the system are shared with the new code (functions defined again are replaced).
+/// Returns the index of the first new code, which can be passed to `vm::run_from`.
+/// On error the system is left as it was before the call.
+pub fn compile_into(sys: &mut NakoSystem, source: &str, options: &NakoOptions) -> Result<usize, NakoError> {
     let mut src = source::Source::new(source);
     // lex
-    let tokens = lexer::lex(&mut src);
+    let tokens = lexer::lex(&mut src)?;
     if options.is_debug {
-        println!("<Tokens>---------------------");
+        eprintln!("<Tokens>---------------------");
         for token in &tokens {
-            print!("[{}]", token);
+            eprint!("[{}]", token);
             if token.kind == TokenKind::EOS {
-                println!();
+                eprintln!();
             }
         }
-        println!("\n</Tokens>--------------------");
+        eprintln!("\n</Tokens>--------------------");
     }
     // parse
-    let ast = parser::parse(tokens);
+    let ast = parser::parse_with_funcs(tokens, &sys.func_table)?;
     if options.is_debug {
         ast.print_tree(0);
     }
-    // bytecode
-    let mut sys = ast_to_bytecode::ast_to_bytecodes(&ast);
+    let start = compile_ast_into(sys, &ast, options)?;
+    if options.optimize {
+        optimize::optimize_from(sys, start);
+    }
+    if options.is_debug {
+        eprint!("{}", asm::disassemble_from(sys, start, Some(source)));
+    }
+    Ok(start)
+}
+
+/// Compile the AST into the existing system (see `compile_into`)
+pub fn compile_ast_into(sys: &mut NakoSystem, ast: &ast::AstNode, options: &NakoOptions) -> Result<usize, NakoError> {
+    let start = sys.codes.len();
+    let const_len = sys.const_list.len();
+    let var_len = sys.var_table.len();
+    let func_table = sys.func_table.clone();
+    ast_to_bytecode::append_bytecodes(sys, ast);
+    if !sys.errors.is_empty() {
+        // throw away the half-compiled codes
+        sys.codes.truncate(start);
+        sys.spans.truncate(start);
+        sys.const_list.truncate(const_len);
+        sys.var_table.truncate(var_len);
+        sys.func_table = func_table;
+        return Err(NakoError::from_errors(std::mem::take(&mut sys.errors)));
+    }
     if options.is_debug {
         sys.is_debug = true;
-        for (i, code) in sys.codes.iter().enumerate() {
-            println!("ByteCode[{}]: kind={:?}, arg1={}, arg2={}, arg3={}",
-                i,
-                code.kind,
-                code.arg1,
-                code.arg2,
-                code.arg3,
-            );
-        }
     }
-    sys
+    Ok(start)
+}
+
+/// Compile more source code into the existing system and run only the new code.
+/// The new code prints to the output of the system.
+pub fn run_more(sys: &mut NakoSystem, source: &str, options: &NakoOptions) -> Result<(), NakoError> {
+    let start = compile_into(sys, source, options)?;
+    if options.is_debug {
+        eprintln!("<Execution>---------------------");
+    }
+    let result = run_vm(sys, start, options);
+    // values left by the failed run must not leak into the next one
+    sys.stack.clear();
+    result
 }
 
 /// Execute easy for test and simple usage.
-pub fn run_easy(source: &str, options: &NakoOptions) -> String {
-    let mut sys = compile(source, options);
+pub fn run_easy(source: &str, options: &NakoOptions) -> Result<String, NakoError> {
+    run_easy_with_system(NakoSystem::new(), source, options)
+}
+
+/// Execute the source with the given system, such as one with host functions registered.
+/// The printed text is collected and returned instead of going to the output of the system.
+pub fn run_easy_with_system(mut sys: NakoSystem, source: &str, options: &NakoOptions) -> Result<String, NakoError> {
+    let buffer = sys.capture_output();
+    compile_into(&mut sys, source, options)?;
     if options.is_debug {
-        println!("<Execution>---------------------");
+        eprintln!("<Execution>---------------------");
     }
-    vm::run(&mut sys);
-    if sys.error_msg.len() > 0 {
-        return sys.error_msg;
+    run_vm(&mut sys, 0, options)?;
+    Ok(buffer.take())
+}
+
+/// Run the codes from `start` on the VM chosen by the options
+pub fn run_vm(sys: &mut NakoSystem, start: usize, options: &NakoOptions) -> Result<(), NakoError> {
+    if options.register_vm {
+        regvm::run_from(sys, start)
+    } else {
+        vm::run_from(sys, start)
     }
-    sys.output
 }
 
-/// Run test code and return output string
+/// Run test code and return output string (or error message)
 pub fn run_test(source: &str) -> String {
-    let options = NakoOptions {
-        is_debug: false,
-    };
-    let outout = run_easy(source, &options);
-    outout.trim().to_string()
+    let options = NakoOptions::new();
+    match run_easy(source, &options) {
+        Ok(output) => output.trim().to_string(),
+        Err(err) => err.to_string(),
+    }
 }
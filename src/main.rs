@@ -1,8 +1,18 @@
 use std::env;
 use std::fs;
+use std::path::Path;
 use std::process;
 
-use nadesiko4::{NakoOptions, run_easy};
+use nadesiko4::repl::{Repl, ReplStatus};
+use nadesiko4::bytecode::NakoSystem;
+use nadesiko4::input::StdinInput;
+use nadesiko4::output::StdoutOutput;
+use nadesiko4::{NakoOptions, bytecode_file, compile, diagnostic, run_more, run_vm};
+
+/// Prompt of the REPL
+const PROMPT: &str = "> ";
+/// Prompt of the REPL while a block is not closed
+const PROMPT_MORE: &str = "… ";
 
 fn main() {
     let mut args: Vec<String> = env::args().skip(1).collect();
@@ -13,12 +23,20 @@ fn main() {
         process::exit(0);
     }
 
-    while args.len() > 0 {
+    while !args.is_empty() {
         let arg = args.remove(0);
         if arg == "--debug" || arg == "-D" || arg == "debug" {
             options.is_debug = true;
             continue;
         }
+        if arg == "--optimize" || arg == "-O" {
+            options.optimize = true;
+            continue;
+        }
+        if arg == "--register-vm" || arg == "-R" {
+            options.register_vm = true;
+            continue;
+        }
         if arg == "--help" || arg == "-h" {
             print_help();
             process::exit(0);            
@@ -27,9 +45,25 @@ fn main() {
             println!("nadesiko4 {}", nadesiko4::version());
             process::exit(0);
         }
+        if arg == "repl" {
+            run_repl(&options);
+            process::exit(0);
+        }
+        if arg == "build" {
+            let (input, output) = parse_build_args(&mut args);
+            if let Err(err) = build_file(&input, &output, &options) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+            continue;
+        }
         if arg == "--eval" || arg == "-e" || arg == "eval" {
+            if args.is_empty() {
+                eprintln!("『{}』の後に実行するコードを指定してください", arg);
+                process::exit(1);
+            }
             let code = args.remove(0);
-            run_code(&code, &options);
+            run_code(&code, "<eval>", &options);
             continue;
         }
         // ファイル実行: nadesiko4 <file>
@@ -41,18 +75,156 @@ fn main() {
     }
 }
 
-/// Run the given file
+/// Run the given file (source code or compiled .nako4c)
 fn run_file(path: &str, options: &NakoOptions) -> Result<(), String> {
-    let src = fs::read_to_string(path).map_err(|e| format!("ファイル読み込みに失敗しました: {}", e))?;
-    run_code(&src, options);
+    let bytes = fs::read(path).map_err(|e| format!("ファイル読み込みに失敗しました: {}", e))?;
+    if bytecode_file::is_compiled(&bytes) {
+        return run_compiled(&bytes, path, options);
+    }
+    let src = String::from_utf8(bytes).map_err(|_| format!("ファイルがUTF-8ではありません: {}", path))?;
+    run_code(&src, path, options);
     Ok(())
 }
 
 /// Run the given code string
-fn run_code(code: &str, options: &NakoOptions) {
-    let output = run_easy(code, options);
-    if !output.is_empty() {
-        println!("{}", output);
+fn run_code(code: &str, file_name: &str, options: &NakoOptions) {
+    // print each line as soon as the script prints it
+    let mut sys = NakoSystem::new();
+    sys.set_output(StdoutOutput);
+    sys.set_input(StdinInput);
+    if let Err(err) = run_more(&mut sys, code, options) {
+        eprint!("{}", diagnostic::render(&err, file_name, code));
+        process::exit(1);
+    }
+}
+
+/// Run the compiled file
+fn run_compiled(bytes: &[u8], path: &str, options: &NakoOptions) -> Result<(), String> {
+    let mut sys = bytecode_file::from_bytes(bytes).map_err(|e| format!("{}: {}", path, e))?;
+    sys.is_debug = options.is_debug;
+    sys.set_output(StdoutOutput);
+    sys.set_input(StdinInput);
+    if let Err(err) = run_vm(&mut sys, 0, options) {
+        // the source is not in the compiled file, so no excerpt is shown
+        eprint!("{}", diagnostic::render(&err, path, ""));
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Read `<file> [-o <output>]` of the build command
+fn parse_build_args(args: &mut Vec<String>) -> (String, String) {
+    if args.is_empty() {
+        eprintln!("コンパイルするファイルを指定してください");
+        process::exit(1);
+    }
+    let input = args.remove(0);
+    let output = if args.first().is_some_and(|a| a == "-o" || a == "--output") {
+        let flag = args.remove(0);
+        if args.is_empty() {
+            eprintln!("『{}』の後に出力先のファイルを指定してください", flag);
+            process::exit(1);
+        }
+        args.remove(0)
+    } else {
+        Path::new(&input).with_extension(bytecode_file::EXTENSION).to_string_lossy().to_string()
+    };
+    (input, output)
+}
+
+/// Compile the source file and save it as a .nako4c file
+fn build_file(input: &str, output: &str, options: &NakoOptions) -> Result<(), String> {
+    let src = fs::read_to_string(input).map_err(|e| format!("ファイル読み込みに失敗しました: {}", e))?;
+    let sys = match compile(&src, options) {
+        Ok(sys) => sys,
+        Err(err) => {
+            eprint!("{}", diagnostic::render(&err, input, &src));
+            process::exit(1);
+        }
+    };
+    let bytes = bytecode_file::to_bytes(&sys).map_err(|e| e.to_string())?;
+    fs::write(output, bytes).map_err(|e| format!("ファイル書き込みに失敗しました: {}", e))?;
+    Ok(())
+}
+
+/// Print the result of one REPL line
+fn print_repl_status(status: ReplStatus) {
+    match status {
+        ReplStatus::NeedMore => {},
+        ReplStatus::Done { echo } => {
+            if let Some(value) = echo {
+                println!("{}", value);
+            }
+        },
+        ReplStatus::Error { error, source } => {
+            eprint!("{}", diagnostic::render(&error, "<repl>", &source));
+        },
+    }
+}
+
+/// Run the REPL with line editing and history
+#[cfg(feature = "repl")]
+fn run_repl(options: &NakoOptions) {
+    use rustyline::DefaultEditor;
+    use rustyline::error::ReadlineError;
+
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("REPLを開始できません: {}", err);
+            process::exit(1);
+        }
+    };
+    let history = env::var("HOME").ok().map(|home| format!("{}/.nadesiko4_history", home));
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+    println!("nadesiko4 {} (Ctrl+Dで終了)", nadesiko4::version());
+    let mut repl = Repl::new();
+    repl.options = options.clone();
+    repl.sys.set_output(StdoutOutput);
+    repl.sys.set_input(StdinInput);
+    loop {
+        let prompt = if repl.is_pending() { PROMPT_MORE } else { PROMPT };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                print_repl_status(repl.feed_line(&line));
+            },
+            // Ctrl+C drops the unfinished input
+            Err(ReadlineError::Interrupted) if repl.is_pending() => repl.clear_pending(),
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                break;
+            }
+        }
+    }
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+}
+
+/// Run the REPL reading lines from stdin (built without the `repl` feature)
+#[cfg(not(feature = "repl"))]
+fn run_repl(options: &NakoOptions) {
+    use std::io::{self, BufRead, Write};
+
+    let mut repl = Repl::new();
+    repl.options = options.clone();
+    repl.sys.set_output(StdoutOutput);
+    repl.sys.set_input(StdinInput);
+    let stdin = io::stdin();
+    loop {
+        print!("{}", if repl.is_pending() { PROMPT_MORE } else { PROMPT });
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => print_repl_status(repl.feed_line(line.trim_end_matches(['\r', '\n']))),
+        }
     }
 }
 
@@ -62,7 +234,10 @@ fn print_help() {
     println!("使い方:");
     println!("  nadesiko4 <file>          ファイルを実行");
     println!("  nadesiko4 -e \"code\"      文字列コードを実行");
+    println!("  nadesiko4 build <file> -o <out>  .nako4cファイルにコンパイル");
+    println!("  nadesiko4 -O <file>        最適化して実行(buildにも指定可)");
+    println!("  nadesiko4 -R <file>        レジスタVMで実行");
     println!("  nadesiko4 --help           ヘルプを表示");
     println!("  nadesiko4 --version        バージョンを表示");
-    println!("  nadesiko4 repl             (未実装) REPL");
+    println!("  nadesiko4 repl             対話モード(REPL)を開始");
 }
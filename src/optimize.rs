@@ -0,0 +1,276 @@
+//! optimize module
+//! Optimizes the compiled codes before running (enabled with `-O`).
+//!
+//! - constant folding: `PushConst a, PushConst b, Add` becomes `PushConst (a+b)`
+//! - peephole: constant conditions, jumps to jumps, jumps to the next code and unused pushes
+//! - Nop (including comments) is removed, positions are kept in `spans`
+//! - constant pool: the same constants are shared and unused ones are dropped
+
+use std::collections::{HashMap, HashSet};
+
+use crate::bytecode::{ByteCode, ByteCodeKind, NakoSystem, Operand};
+use crate::source::SourcePos;
+use crate::value::Value;
+use crate::vm;
+
+/// Optimize all codes of the system
+pub fn optimize(sys: &mut NakoSystem) {
+    optimize_from(sys, 0);
+}
+
+/// Optimize the codes from `start` (such as the codes added by `compile_into`)
+pub fn optimize_from(sys: &mut NakoSystem, start: usize) {
+    loop {
+        let mut changed = fold_constants(sys, start);
+        changed |= peephole(sys, start);
+        changed |= remove_nops(sys, start);
+        if !changed {
+            break;
+        }
+    }
+    dedup_constants(sys);
+    // the constants of the earlier codes may be renumbered
+    sys.codes_changed();
+}
+
+/// Positions that codes jump to (their previous code may not be merged with them)
+fn jump_targets(sys: &NakoSystem) -> HashSet<usize> {
+    let mut targets: HashSet<usize> = sys.func_table.funcs.iter()
+        .filter(|func| func.sys_func.is_none())
+        .map(|func| func.addr)
+        .collect();
+    for code in &sys.codes {
+        for (op, arg) in code.kind.operands().iter().zip([code.arg1, code.arg2, code.arg3]) {
+            if *op == Operand::Addr {
+                targets.insert(arg);
+            }
+        }
+    }
+    targets
+}
+
+fn is_binary_op(kind: ByteCodeKind) -> bool {
+    matches!(kind,
+        ByteCodeKind::Add | ByteCodeKind::Sub | ByteCodeKind::Mul | ByteCodeKind::Div
+        | ByteCodeKind::Eq | ByteCodeKind::NotEq | ByteCodeKind::Gt | ByteCodeKind::GtEq
+        | ByteCodeKind::Lt | ByteCodeKind::LtEq)
+}
+
+/// Calculate operators on two constants at compile time.
+/// Operators that fail (such as division by zero) are left to report the error at runtime.
+fn fold_constants(sys: &mut NakoSystem, start: usize) -> bool {
+    let targets = jump_targets(sys);
+    let mut changed = false;
+    let mut i = start;
+    while i + 2 < sys.codes.len() {
+        let (a, b, op) = (sys.codes[i], sys.codes[i + 1], sys.codes[i + 2]);
+        let foldable = a.kind == ByteCodeKind::PushConst && b.kind == ByteCodeKind::PushConst
+            && is_binary_op(op.kind) && !targets.contains(&(i + 1)) && !targets.contains(&(i + 2));
+        if foldable
+            && let (Some(left), Some(right)) = (sys.const_list.get(a.arg1), sys.const_list.get(b.arg1))
+            && let Ok(value) = vm::binary_op(op.kind, left, right) {
+            sys.const_list.push(value);
+            sys.codes[i] = ByteCode::new(ByteCodeKind::PushConst, sys.const_list.len() - 1, 0, 0);
+            // keep the position of the operator for errors in later codes
+            sys.spans[i] = sys.spans[i + 2];
+            sys.codes[i + 1] = ByteCode::new_nop();
+            sys.codes[i + 2] = ByteCode::new_nop();
+            changed = true;
+            i += 3;
+            continue;
+        }
+        i += 1;
+    }
+    changed
+}
+
+/// Simplify short sequences of codes
+fn peephole(sys: &mut NakoSystem, start: usize) -> bool {
+    let targets = jump_targets(sys);
+    let mut changed = false;
+    for i in start..sys.codes.len() {
+        let code = sys.codes[i];
+        let next = sys.codes.get(i + 1).copied();
+        match (code.kind, next) {
+            // a constant condition: always jump or never jump
+            (ByteCodeKind::PushConst, Some(jump))
+                if matches!(jump.kind, ByteCodeKind::JumpIfFalse | ByteCodeKind::JumpIfTrue)
+                    && !targets.contains(&(i + 1)) =>
+            {
+                let Some(value) = sys.const_list.get(code.arg1) else { continue };
+                let jumps = value.to_bool() == (jump.kind == ByteCodeKind::JumpIfTrue);
+                sys.codes[i] = ByteCode::new_nop();
+                sys.codes[i + 1] = if jumps { ByteCode::new(ByteCodeKind::Jump, jump.arg1, 0, 0) } else { ByteCode::new_nop() };
+                changed = true;
+            },
+            // a value pushed only to be thrown away
+            (ByteCodeKind::PushConst | ByteCodeKind::PushVariable, Some(pop))
+                if pop.kind == ByteCodeKind::Pop && !targets.contains(&(i + 1)) =>
+            {
+                sys.codes[i] = ByteCode::new_nop();
+                sys.codes[i + 1] = ByteCode::new_nop();
+                changed = true;
+            },
+            (ByteCodeKind::Jump | ByteCodeKind::JumpIfFalse | ByteCodeKind::JumpIfTrue, _) => {
+                // jump to the final target of a chain of jumps
+                let target = final_target(sys, code.arg1);
+                if target != code.arg1 {
+                    sys.codes[i].arg1 = target;
+                    changed = true;
+                }
+                // jump to the next code does nothing (a conditional jump still pops the condition)
+                if code.kind == ByteCodeKind::Jump && target == i + 1 {
+                    sys.codes[i] = ByteCode::new_nop();
+                    changed = true;
+                }
+            },
+            _ => {},
+        }
+    }
+    changed
+}
+
+/// Follow the unconditional jumps from the address
+fn final_target(sys: &NakoSystem, mut addr: usize) -> usize {
+    // the limit stops at loops made only of jumps
+    for _ in 0..sys.codes.len() {
+        match sys.codes.get(addr) {
+            Some(code) if code.kind == ByteCodeKind::Jump && code.arg1 != addr => addr = code.arg1,
+            _ => break,
+        }
+    }
+    addr
+}
+
+/// Remove Nop codes from `start`, and fix the jump targets and function addresses
+fn remove_nops(sys: &mut NakoSystem, start: usize) -> bool {
+    let removable = |code: &ByteCode| code.kind == ByteCodeKind::Nop;
+    if !sys.codes[start..].iter().any(removable) {
+        return false;
+    }
+    // new_index[i] is the new position of the code i, or of the next kept code if removed
+    let mut new_index = Vec::with_capacity(sys.codes.len() + 1);
+    let mut kept = 0;
+    for (i, code) in sys.codes.iter().enumerate() {
+        new_index.push(kept);
+        if i < start || !removable(code) {
+            kept += 1;
+        }
+    }
+    new_index.push(kept);
+    let codes = std::mem::take(&mut sys.codes);
+    let spans = std::mem::take(&mut sys.spans);
+    for (i, mut code) in codes.into_iter().enumerate() {
+        if i >= start && removable(&code) {
+            continue;
+        }
+        let ops = code.kind.operands();
+        for (slot, op) in ops.iter().enumerate() {
+            if *op != Operand::Addr {
+                continue;
+            }
+            let arg = match slot {
+                0 => &mut code.arg1,
+                1 => &mut code.arg2,
+                _ => &mut code.arg3,
+            };
+            *arg = new_index.get(*arg).copied().unwrap_or(*arg);
+        }
+        sys.codes.push(code);
+        sys.spans.push(spans.get(i).copied().unwrap_or(SourcePos::zero()));
+    }
+    for func in sys.func_table.funcs.iter_mut().filter(|func| func.sys_func.is_none()) {
+        func.addr = new_index.get(func.addr).copied().unwrap_or(func.addr);
+    }
+    true
+}
+
+/// Share the same constants and drop the unused ones.
+/// Nothing is changed if a code has a bad index, so that the verifier still rejects it.
+fn dedup_constants(sys: &mut NakoSystem) {
+    let in_range = |code: &ByteCode| code.kind != ByteCodeKind::PushConst || code.arg1 < sys.const_list.len();
+    if !sys.codes.iter().all(in_range) {
+        return;
+    }
+    let mut const_list: Vec<Value> = Vec::new();
+    // the key tells -0 from 0 and strings from numbers
+    let mut index_of: HashMap<String, usize> = HashMap::new();
+    let old_list = std::mem::take(&mut sys.const_list);
+    for code in sys.codes.iter_mut() {
+        if code.kind != ByteCodeKind::PushConst {
+            continue;
+        }
+        let value = &old_list[code.arg1];
+        let key = format!("{:?}", value);
+        code.arg1 = *index_of.entry(key).or_insert_with(|| {
+            const_list.push(value.clone());
+            const_list.len() - 1
+        });
+    }
+    sys.const_list = const_list;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::disassemble;
+
+    fn optimized(code: &str) -> NakoSystem {
+        let mut sys = crate::compile(code, &crate::NakoOptions::new()).unwrap();
+        optimize(&mut sys);
+        sys
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let sys = optimized("A=2+3*4");
+        assert_eq!(disassemble(&sys, None), ".line 1\n    PushConst 14\n    Let A\n");
+        assert_eq!(sys.const_list, vec![Value::from_number(14.0)]);
+    }
+
+    #[test]
+    fn keeps_runtime_errors() {
+        let sys = optimized("A=1/0");
+        assert_eq!(disassemble(&sys, None), ".line 1\n    PushConst 1\n    PushConst 0\n    Div\n    Let A\n");
+    }
+
+    #[test]
+    fn dedups_constants() {
+        let sys = optimized("A=「x」\nB=「x」\nC=1\nD=1\nE=「1」");
+        assert_eq!(sys.const_list, vec![Value::from_str("x"), Value::from_number(1.0), Value::from_str("1")]);
+    }
+
+    #[test]
+    fn simplifies_constant_conditions() {
+        // 条件が定数なら分岐しない
+        let sys = optimized("もし1>2ならば\n「a」を表示\n違えば\n「b」を表示\nここまで");
+        let text = disassemble(&sys, None);
+        assert!(!text.contains("JumpIfFalse"), "{}", text);
+        assert!(text.starts_with(".line 1\n    Jump L"), "{}", text);
+    }
+
+    #[test]
+    fn fixes_jump_targets() {
+        let code = "●(Nを)倍増とは\n# 説明\nN*2で戻る\nここまで\nA=0\nA<3の間\nA=A+1\nここまで\nAを倍増して表示";
+        let plain = crate::compile(code, &crate::NakoOptions::new()).unwrap();
+        let mut sys = optimized(code);
+        assert!(!sys.codes.iter().any(|code| code.kind == ByteCodeKind::Nop));
+        // 行の情報(EOS)は残す
+        let count_eos = |sys: &NakoSystem| sys.codes.iter().filter(|code| code.kind == ByteCodeKind::EOS).count();
+        assert_eq!(count_eos(&sys), count_eos(&plain));
+        let buffer = sys.capture_output();
+        crate::vm::run(&mut sys).unwrap();
+        assert_eq!(buffer.take(), "6\n");
+    }
+
+    #[test]
+    fn keeps_bad_const_index() {
+        // 範囲外の定数番号は別の定数を指すように変えず、検証で弾かれるようにする
+        let mut sys = crate::asm::assemble("PushConst 1\nLet A\nPushConst 1\nLet B\nPushConst #3\nLet C").unwrap();
+        let const_len = sys.const_list.len();
+        optimize(&mut sys);
+        assert_eq!(sys.const_list.len(), const_len);
+        assert_eq!(sys.codes[4].arg1, 3);
+        assert!(crate::verify::verify(&sys).is_err());
+    }
+}
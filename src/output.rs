@@ -0,0 +1,129 @@
+//! output module
+//! Destinations of the text printed by scripts (stdout, a buffer, a callback or a file).
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Destination of the text printed by scripts
+pub trait NakoOutput {
+    /// Write the text (it may or may not end with a newline)
+    fn write(&mut self, text: &str);
+    /// Flush the written text, called when the program finishes
+    fn flush(&mut self) {}
+}
+
+/// Shared handle of the output, so that clones of a NakoSystem print to the same place.
+/// It is Send, so a NakoSystem can be moved to another thread.
+#[derive(Clone)]
+pub struct OutputSink(Arc<Mutex<dyn NakoOutput + Send>>);
+impl OutputSink {
+    pub fn new<T: NakoOutput + Send + 'static>(output: T) -> Self {
+        OutputSink(Arc::new(Mutex::new(output)))
+    }
+    pub fn write(&self, text: &str) {
+        lock(&self.0).write(text);
+    }
+    pub fn flush(&self) {
+        lock(&self.0).flush();
+    }
+}
+
+/// Lock the mutex, even if a thread panicked while holding it
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+impl fmt::Debug for OutputSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OutputSink")
+    }
+}
+
+/// Print to stdout. Stdout is line buffered, so each line appears when it ends
+/// and a text without a newline (such as a prompt) appears on `flush`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutOutput;
+impl NakoOutput for StdoutOutput {
+    fn write(&mut self, text: &str) {
+        let _ = io::stdout().lock().write_all(text.as_bytes());
+    }
+    fn flush(&mut self) {
+        let _ = io::stdout().lock().flush();
+    }
+}
+
+/// Keep the text in memory. Clones share the same buffer, so keep one to read the text.
+#[derive(Clone, Debug, Default)]
+pub struct BufferOutput {
+    text: Arc<Mutex<String>>,
+}
+impl BufferOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Copy of the text written so far
+    pub fn text(&self) -> String {
+        lock(&self.text).clone()
+    }
+    /// Take the text written so far and clear the buffer
+    pub fn take(&self) -> String {
+        std::mem::take(&mut *lock(&self.text))
+    }
+}
+impl NakoOutput for BufferOutput {
+    fn write(&mut self, text: &str) {
+        lock(&self.text).push_str(text);
+    }
+}
+
+/// Pass the text to a callback
+pub struct CallbackOutput<F: FnMut(&str)>(pub F);
+impl<F: FnMut(&str)> NakoOutput for CallbackOutput<F> {
+    fn write(&mut self, text: &str) {
+        (self.0)(text);
+    }
+}
+
+/// Write to any `io::Write`, such as a file
+pub struct WriterOutput<W: Write>(pub W);
+impl<W: Write> NakoOutput for WriterOutput<W> {
+    fn write(&mut self, text: &str) {
+        let _ = self.0.write_all(text.as_bytes());
+    }
+    fn flush(&mut self) {
+        let _ = self.0.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_is_shared() {
+        let buffer = BufferOutput::new();
+        let sink = OutputSink::new(buffer.clone());
+        sink.write("abc\n");
+        sink.clone().write("def");
+        assert_eq!(buffer.text(), "abc\ndef");
+        assert_eq!(buffer.take(), "abc\ndef");
+        assert_eq!(buffer.text(), "");
+    }
+
+    #[test]
+    fn callback_and_writer() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines2 = lines.clone();
+        let sink = OutputSink::new(CallbackOutput(move |s: &str| lines2.lock().unwrap().push(s.to_string())));
+        sink.write("a");
+        // 別スレッドからも同じ出力先に書ける
+        let sink2 = sink.clone();
+        std::thread::spawn(move || sink2.write("b")).join().unwrap();
+        assert_eq!(*lines.lock().unwrap(), vec!["a", "b"]);
+
+        let mut writer = WriterOutput(Vec::new());
+        writer.write("xyz");
+        writer.flush();
+        assert_eq!(writer.0, b"xyz");
+    }
+}
@@ -1,11 +1,20 @@
 /// parser module
+use std::collections::HashMap;
+
 use crate::token::{Token, TokenKind};
 use crate::ast::{AstNode, AstKind};
+use crate::bytecode::NakoFuncTable;
+use crate::error::{ErrorCode, NakoError};
+use crate::source::SourcePos;
 
 pub struct Parser {
     tokens: Vec<Token>,
     index: usize,
     stack: Vec<AstNode>,
+    /// 関数名と引数ごとに受け付ける助詞の一覧
+    funcs: HashMap<String, Vec<Vec<String>>>,
+    /// 解析中に見つかったエラー
+    errors: Vec<NakoError>,
 }
 impl Parser {
     /// Create a new parser instance
@@ -14,13 +23,25 @@ impl Parser {
             tokens,
             index: 0,
             stack: Vec::new(),
+            funcs: HashMap::new(),
+            errors: Vec::new(),
         }
     }
+    /// 文法エラーを記録する
+    pub fn error(&mut self, code: ErrorCode, pos: SourcePos, message: &str) {
+        self.errors.push(NakoError::parser(code, pos, message));
+    }
+    /// トークンの位置と長さで文法エラーを記録する
+    pub fn error_at(&mut self, code: ErrorCode, token: &Token, message: &str) {
+        let len = token.value.as_ref().map_or(1, |v| v.chars().count().max(1));
+        self.errors.push(NakoError::parser(code, token.pos, message).with_len(len));
+    }
     /// Check the current token without advancing
     pub fn peek(&self) -> Option<&Token> {
         self.tokens.get(self.index)
     }
     /// Get the next token and advance the cursor
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<&Token> {
         let tok = self.tokens.get(self.index);
         if tok.is_some() {
@@ -70,7 +91,18 @@ impl Parser {
                 return false;
             }
         }
-        return true;
+        true
+    }
+    /// 現在位置からEOSを読み飛ばした先のトークンが指定の種類かテストする
+    pub fn test_kind_after_eos(&self, kind: TokenKind) -> bool {
+        let mut i = self.index;
+        while let Some(tok) = self.tokens.get(i) {
+            if tok.kind != TokenKind::EOS {
+                return tok.kind == kind;
+            }
+            i += 1;
+        }
+        false
     }
     /// Test if the current token is an operator
     pub fn test_operator(&self) -> bool {
@@ -82,25 +114,77 @@ impl Parser {
 }
 
 /// Parse the list of tokens into an AST.
-pub fn parse(tokens: Vec<Token>) -> AstNode {
+pub fn parse(tokens: Vec<Token>) -> Result<AstNode, NakoError> {
+    let mut funcs = NakoFuncTable::new();
+    crate::sys_func::register_all(&mut funcs);
+    parse_with_funcs(tokens, &funcs)
+}
+
+/// Parse the list of tokens into an AST, resolving the functions in the table as calls.
+pub fn parse_with_funcs(tokens: Vec<Token>, funcs: &NakoFuncTable) -> Result<AstNode, NakoError> {
     let mut parser = Parser::new(tokens);
+    for func in funcs.funcs.iter() {
+        parser.funcs.insert(func.name.clone(), func.josi_list.clone());
+    }
+    // 関数は定義より前でも呼べるように、先に関数定義の見出しを読んでおく
+    scan_func_defs(&mut parser);
     let mut root = AstNode::new(AstKind::Node);
     parse_sentences(&mut parser, &mut root);
     // スタックに余剰があればエラーにする
-    if !parser.stack.is_empty() {
-        println!("[ERROR][Parser] Stack is not empty after parsing: {:?}", parser.stack);
+    if let Some(node) = parser.stack.first() {
+        let pos = node.pos;
+        parser.error(ErrorCode::UnusedValue, pos, "使われていない値があります");
     }
-    return root;
+    if !parser.errors.is_empty() {
+        return Err(NakoError::from_errors(parser.errors));
+    }
+    Ok(root)
 }
 
 /// 複数文の解析
 fn parse_sentences(parser: &mut Parser, parent: &mut AstNode) -> bool {
-    while parser.has_more() {
-        parse_sentence(parser, parent);
-    }
+    parse_block(parser, parent, &[]);
     true
 }
 
+/// ブロックの解析 --- 終端トークン(違えば/ここまで等)の手前まで文を読む
+fn parse_block(parser: &mut Parser, parent: &mut AstNode, end_kinds: &[TokenKind]) {
+    while parser.has_more() && !parser.test_kinds(end_kinds) {
+        let base = parser.stack.len();
+        let error_count = parser.errors.len();
+        let index = parser.get_index();
+        let result = parse_sentence(parser, parent);
+        if parser.errors.len() > error_count {
+            // エラーがあれば次の文から解析を続ける
+            recover_to_eos(parser, base, end_kinds);
+        } else if !result && parser.get_index() == index {
+            // 解析できないトークンは読み飛ばす
+            parser.next();
+        }
+    }
+}
+
+/// エラーからの回復 --- 文の途中の値を捨て、文末(EOS)まで読み飛ばす
+fn recover_to_eos(parser: &mut Parser, base: usize, end_kinds: &[TokenKind]) {
+    parser.stack.truncate(base);
+    // 既に文末まで読んでいれば何もしない
+    if parser.get_index() > 0 && matches!(parser.tokens[parser.get_index() - 1].kind,
+        TokenKind::EOS | TokenKind::BlockEnd) {
+        return;
+    }
+    while parser.has_more() && !parser.test_kinds(end_kinds) {
+        if parser.next().unwrap().kind == TokenKind::EOS {
+            break;
+        }
+    }
+}
+
+/// エラーのあった構文の本体を読み飛ばす --- 「ここまで」との対応がずれないようにする
+fn skip_block_body(parser: &mut Parser, start_t: &Token) {
+    let mut body = AstNode::new_pos(AstKind::Node, start_t.pos);
+    parse_block_body(parser, &mut body, start_t);
+}
+
 /// 短文の解析
 fn parse_sentence(parser: &mut Parser, parent: &mut AstNode) -> bool {
     // Nop?
@@ -115,13 +199,45 @@ fn parse_sentence(parser: &mut Parser, parent: &mut AstNode) -> bool {
     if parser.test_kind_array(&[TokenKind::Word, TokenKind::Eq]) {
         return parse_let(parser, parent);
     }
+    // もし文
+    if parser.test_kind(TokenKind::If) {
+        return parse_if(parser, parent);
+    }
+    // 抜ける・続ける
+    if parser.test_kinds(&[TokenKind::Break, TokenKind::Continue]) {
+        return parse_break_continue(parser, parent);
+    }
+    // 関数定義
+    if parser.test_kind(TokenKind::FuncDef) {
+        return parse_func_def(parser, parent);
+    }
     // 一般的な文
     {      
+        let base = parser.stack.len();
         while parse_value(parser) {
             // 値の解析が成功した場合、次のトークンへ
         }
+        if parse_return(parser, parent) {
+            return true;
+        }
         // 特殊文の解析 - 現在のトークンをチェック
-        if parse_print(parser, parent) {
+        if parse_times(parser, parent) {
+            return true;
+        }
+        if parse_for(parser, parent) {
+            return true;
+        }
+        if parse_while(parser, parent) {
+            return true;
+        }
+        if parse_foreach(parser, parent) {
+            return true;
+        }
+        // 文末に残った値(関数呼び出しの結果など)は「それ」に代入する
+        if parser.stack.len() > base {
+            for node in parser.stack.split_off(base) {
+                parent.add_child(new_let_sore(node));
+            }
             return true;
         }
     }
@@ -131,9 +247,9 @@ fn parse_sentence(parser: &mut Parser, parent: &mut AstNode) -> bool {
     }
     // 不明なトークン
     if parser.has_more() {
-        let t = parser.peek().unwrap();
-        println!("[ERROR][Parser] Unknown token at {}:{}: {:?}", 
-            t.pos.line, t.pos.column, t);
+        let t = parser.peek().unwrap().clone();
+        parser.error_at(ErrorCode::UnknownToken, &t,
+            &format!("『{}』はここに書けません", t.value.as_deref().unwrap_or("")));
     }
     false
 }
@@ -150,10 +266,12 @@ fn parse_let(parser: &mut Parser, parent: &mut AstNode) -> bool {
                 var_node.value = crate::value::Value::from_string(var_name_s);
             }
             // 右辺の式(1つ)を解析
+            let error_count = parser.errors.len();
             if !parse_value(parser) {
-                // 値の解析が成功した場合、次のトークンへ
-                println!("[ERROR][Parser] Expected expression after '=' at {}:{}", 
-                    eq_t.pos.line, eq_t.pos.column);
+                // 式の中で既にエラーが報告されていれば、重ねて報告しない
+                if parser.errors.len() == error_count {
+                    parser.error_at(ErrorCode::ExpectedExpression, &eq_t, "『=』の後に式がありません");
+                }
                 return false;
             }
             // スタックから右辺のASTノードを取り出す
@@ -165,14 +283,482 @@ fn parse_let(parser: &mut Parser, parent: &mut AstNode) -> bool {
                 parent.add_child(let_node);
                 return true;
             } else {
-                println!("[ERROR][Parser] Expected expression after '=' at {}:{}", 
-                    eq_t.pos.line, eq_t.pos.column);
+                parser.error_at(ErrorCode::ExpectedExpression, &eq_t, "『=』の後に式がありません");
             }
         }
     }
     false
 }
 
+/// もし文の解析
+/// 「もし(条件)ならば(文)」の一行形式と、「もし(条件)ならば」の後に改行して
+/// 「違えば」「ここまで」で区切る複数行形式に対応する
+fn parse_if(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    let if_t = parser.next().unwrap().clone();
+    let cond = match parse_condition(parser, &if_t) {
+        Some(node) => node,
+        None => return false,
+    };
+    let mut then_node = AstNode::new_pos(AstKind::Node, if_t.pos);
+    let mut else_node = AstNode::new_pos(AstKind::Node, if_t.pos);
+    let is_block = parser.test_kind(TokenKind::EOS);
+    if is_block {
+        // 複数行のもし文
+        parse_block(parser, &mut then_node, &[TokenKind::Else, TokenKind::BlockEnd]);
+        if parser.test_kind(TokenKind::Else) {
+            parser.next();
+            parse_else(parser, &mut else_node);
+        } else if parser.test_kind(TokenKind::BlockEnd) {
+            parser.next();
+        } else {
+            parser.error_at(ErrorCode::MissingBlockEnd, &if_t, "『もし』に対応する『ここまで』がありません");
+        }
+    } else {
+        // 一行のもし文
+        parse_sentence(parser, &mut then_node);
+        // 次の行が「違えば」で始まるなら、それを読む
+        if parser.test_kind_after_eos(TokenKind::Else) {
+            while parser.test_kind(TokenKind::EOS) {
+                parse_eos(parser, &mut then_node);
+            }
+            parser.next();
+            parse_else(parser, &mut else_node);
+        }
+    }
+    let mut if_node = AstNode::new_pos(AstKind::If, if_t.pos);
+    if_node.add_child(cond);
+    if_node.add_child(then_node);
+    if_node.add_child(else_node);
+    parent.add_child(if_node);
+    true
+}
+
+/// 「違えば」以降の解析
+/// 直後が改行なら「ここまで」までをブロックとして読み、そうでなければ一文を読む
+fn parse_else(parser: &mut Parser, else_node: &mut AstNode) {
+    if !parser.test_kind(TokenKind::EOS) {
+        // 「違えば〜」の一行形式 (「違えばもし〜」も含む)
+        parse_sentence(parser, else_node);
+        return;
+    }
+    parse_block(parser, else_node, &[TokenKind::BlockEnd]);
+    if parser.test_kind(TokenKind::BlockEnd) {
+        parser.next();
+    } else {
+        parser.error(ErrorCode::MissingBlockEnd, else_node.pos, "『違えば』に対応する『ここまで』がありません");
+    }
+}
+
+/// 「N回」の解析
+fn parse_times(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    if !parser.test_kind(TokenKind::Times) {
+        return false;
+    }
+    let t = parser.next().unwrap().clone();
+    let mut node = AstNode::new_pos(AstKind::Times, t.pos);
+    match parser.stack.pop() {
+        Some(count) => node.add_child(count),
+        None => {
+            parser.error_at(ErrorCode::MissingTimesCount, &t, "『回』の前に回数がありません");
+            skip_block_body(parser, &t);
+            return false;
+        }
+    }
+    let mut body = AstNode::new_pos(AstKind::Node, t.pos);
+    parse_block_body(parser, &mut body, &t);
+    node.add_child(body);
+    parent.add_child(node);
+    true
+}
+
+/// 「IをAからBまでCずつ繰り返す」の解析
+/// ループ変数(を)と増分(ずつ)は省略可能で、ループ変数を省略した場合は「対象」に代入する
+fn parse_for(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    if !parser.test_kind(TokenKind::Repeat) {
+        return false;
+    }
+    let t = parser.next().unwrap().clone();
+    // スタックから助詞に応じて引数を取り出す
+    let mut var_node: Option<AstNode> = None;
+    let mut from_node: Option<AstNode> = None;
+    let mut to_node: Option<AstNode> = None;
+    let mut step_node: Option<AstNode> = None;
+    while let Some(top) = parser.stack.last() {
+        let slot = match top.josi.as_deref() {
+            Some("を") => &mut var_node,
+            Some("から") => &mut from_node,
+            Some("まで") => &mut to_node,
+            Some("ずつ") => &mut step_node,
+            _ => break,
+        };
+        if slot.is_some() {
+            break;
+        }
+        *slot = parser.stack.pop();
+    }
+    let (Some(from_node), Some(to_node)) = (from_node, to_node) else {
+        parser.error_at(ErrorCode::InvalidRepeat, &t, "『繰り返す』には『AからBまで』の指定が必要です");
+        skip_block_body(parser, &t);
+        return false;
+    };
+    let var_node = match var_node {
+        Some(node) if node.kind == AstKind::Variable => node,
+        Some(node) => {
+            parser.error(ErrorCode::InvalidRepeat, node.pos, "『繰り返す』のループ変数が不正です");
+            skip_block_body(parser, &t);
+            return false;
+        },
+        None => {
+            let mut node = AstNode::new_pos(AstKind::Variable, t.pos);
+            node.value = crate::value::Value::from_str("対象");
+            node
+        },
+    };
+    let mut node = AstNode::new_pos(AstKind::For, t.pos);
+    node.add_child(var_node);
+    node.add_child(from_node);
+    node.add_child(to_node);
+    node.add_child(step_node.unwrap_or_else(AstNode::new_nop));
+    let mut body = AstNode::new_pos(AstKind::Node, t.pos);
+    parse_block_body(parser, &mut body, &t);
+    node.add_child(body);
+    parent.add_child(node);
+    true
+}
+
+/// 「(条件)の間」の解析
+fn parse_while(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    if !parser.test_kind(TokenKind::While) {
+        return false;
+    }
+    let t = parser.next().unwrap().clone();
+    if !is_stack_top_josi(parser, &["の"]) {
+        parser.error_at(ErrorCode::InvalidWhile, &t, "『間』の前に『(条件)の』がありません");
+        skip_block_body(parser, &t);
+        return false;
+    }
+    // 条件の値の個数を判定する --- [A] / [Aが, B] / [Aが, B, 以上]
+    let len = parser.stack.len();
+    let is_ga = |node: &AstNode| matches!(node.josi.as_deref(), Some("が") | Some("は"));
+    let top = &parser.stack[len - 1];
+    let count = if len >= 3 && top.kind == AstKind::Variable
+        && get_compare_word_kind(&top.value.to_string()).is_some()
+        && parser.stack[len - 2].josi.is_none() && is_ga(&parser.stack[len - 3]) {
+        3
+    } else if len >= 2 && is_ga(&parser.stack[len - 2]) {
+        2
+    } else {
+        1
+    };
+    let args = parser.stack.split_off(len - count);
+    let mut cond = match build_condition(args, &t) {
+        Ok(cond) => cond,
+        Err(err) => {
+            // 本体は読み進めるため、条件は空のノードにしておく
+            parser.errors.push(err);
+            AstNode::new_nop()
+        }
+    };
+    cond.josi = None;
+    let mut node = AstNode::new_pos(AstKind::While, t.pos);
+    node.add_child(cond);
+    let mut body = AstNode::new_pos(AstKind::Node, t.pos);
+    parse_block_body(parser, &mut body, &t);
+    node.add_child(body);
+    parent.add_child(node);
+    true
+}
+
+/// 「(配列)を反復」の解析
+fn parse_foreach(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    if !parser.test_kind(TokenKind::Foreach) {
+        return false;
+    }
+    let t = parser.next().unwrap().clone();
+    if !is_stack_top_josi(parser, &["を"]) {
+        parser.error_at(ErrorCode::InvalidForeach, &t, "『反復』の前に『(配列)を』がありません");
+        skip_block_body(parser, &t);
+        return false;
+    }
+    let mut target = parser.stack.pop().unwrap();
+    target.josi = None;
+    let mut node = AstNode::new_pos(AstKind::Foreach, t.pos);
+    node.add_child(target);
+    let mut body = AstNode::new_pos(AstKind::Node, t.pos);
+    parse_block_body(parser, &mut body, &t);
+    node.add_child(body);
+    parent.add_child(node);
+    true
+}
+
+/// 関数定義の見出し
+struct FuncHeader {
+    name: String,
+    /// 書かれたままの関数名(送り仮名つき)
+    written_name: String,
+    /// (引数名, 助詞) の一覧
+    args: Vec<(String, String)>,
+    /// 見出しの次のトークン位置
+    next_index: usize,
+}
+
+/// 関数定義の見出し「●(AとBを)関数名とは」または「●関数名(AとBを)」を読む
+fn read_func_header(tokens: &[Token], start: usize) -> Option<FuncHeader> {
+    let mut index = start + 1; // skip ●
+    let mut args: Vec<(String, String)> = Vec::new();
+    // 引数の一覧「(AとBを)」を読む
+    let read_args = |index: &mut usize, args: &mut Vec<(String, String)>| {
+        *index += 1; // skip (
+        while let Some(t) = tokens.get(*index) {
+            *index += 1;
+            match t.kind {
+                TokenKind::ParenR => return true,
+                TokenKind::Word => {
+                    let name = t.value.clone().unwrap_or_default();
+                    let josi = t.josi.clone().unwrap_or_default();
+                    args.push((name, josi));
+                },
+                _ => return false,
+            }
+        }
+        false
+    };
+    if tokens.get(index)?.kind == TokenKind::ParenL && !read_args(&mut index, &mut args) {
+        return None;
+    }
+    let name_t = tokens.get(index)?;
+    if name_t.kind != TokenKind::Word {
+        return None;
+    }
+    let name = name_t.value.clone()?;
+    let written_name = name_t.raw.clone().unwrap_or_else(|| name.clone());
+    index += 1;
+    if args.is_empty() && tokens.get(index).is_some_and(|t| t.kind == TokenKind::ParenL)
+        && !read_args(&mut index, &mut args) {
+        return None;
+    }
+    Some(FuncHeader { name, written_name, args, next_index: index })
+}
+
+/// 全ての関数定義の見出しを読み、関数名と引数の助詞を登録する
+fn scan_func_defs(parser: &mut Parser) {
+    for i in 0..parser.tokens.len() {
+        if parser.tokens[i].kind != TokenKind::FuncDef {
+            continue;
+        }
+        if let Some(header) = read_func_header(&parser.tokens, i) {
+            let josi_list = header.args.into_iter().map(|(_, josi)| vec![josi]).collect();
+            parser.funcs.insert(header.name, josi_list);
+        }
+    }
+}
+
+/// 関数定義の解析
+fn parse_func_def(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    let t = parser.peek().unwrap().clone();
+    let Some(header) = read_func_header(&parser.tokens, parser.get_index()) else {
+        parser.error_at(ErrorCode::InvalidFuncDef, &t, "関数定義の書式が不正です");
+        // 見出しの行を読み飛ばし、本体も読み飛ばす
+        while parser.has_more() && !parser.test_kind(TokenKind::EOS) {
+            parser.next();
+        }
+        skip_block_body(parser, &t);
+        return false;
+    };
+    parser.set_index(header.next_index);
+    let mut node = AstNode::new_pos(AstKind::FuncDef, t.pos);
+    // 関数名は書かれたままにして、登録するときに送り仮名を省略する
+    node.value = crate::value::Value::from_string(header.written_name);
+    let mut args_node = AstNode::new_pos(AstKind::Node, t.pos);
+    for (arg_name, josi) in header.args {
+        let mut arg = AstNode::new_pos(AstKind::Variable, t.pos);
+        arg.value = crate::value::Value::from_string(arg_name);
+        arg.josi = Some(josi);
+        args_node.add_child(arg);
+    }
+    node.add_child(args_node);
+    let mut body = AstNode::new_pos(AstKind::Node, t.pos);
+    parse_block_body(parser, &mut body, &t);
+    node.add_child(body);
+    parent.add_child(node);
+    true
+}
+
+/// 関数呼び出しの解析 --- スタックから助詞の一致する引数を取り出す
+/// 省略された引数には「それ」を渡す
+fn parse_call(parser: &mut Parser, token: &Token) -> bool {
+    let name = token.value.clone().unwrap_or_default();
+    let josi_list = parser.funcs[&name].clone();
+    let mut args: Vec<Option<AstNode>> = vec![None; josi_list.len()];
+    // スタックの上から順に、助詞が一致する未設定の引数に割り当てる
+    while let Some(top) = parser.stack.last() {
+        let top_josi = top.josi.as_deref().unwrap_or("");
+        let slot = josi_list.iter().enumerate()
+            .rposition(|(i, josi)| josi.iter().any(|j| j == top_josi) && args[i].is_none());
+        let Some(slot) = slot else {
+            break;
+        };
+        let mut arg = parser.stack.pop().unwrap();
+        arg.josi = None;
+        args[slot] = Some(arg);
+    }
+    let mut node = AstNode::new_pos(AstKind::Call, token.pos);
+    node.value = crate::value::Value::from_string(name);
+    node.josi = token.josi.clone();
+    for arg in args {
+        node.add_child(arg.unwrap_or_else(|| {
+            let mut sore = AstNode::new_pos(AstKind::Variable, token.pos);
+            sore.value = crate::value::Value::from_str("それ");
+            sore
+        }));
+    }
+    parser.stack.push(node);
+    // 助詞がない場合のみ、次のトークンをpeek()して演算子なら処理
+    if token.josi.is_none() {
+        process_operators(parser, 0);
+    }
+    true
+}
+
+/// 「(値)で戻る」「戻る」の解析
+fn parse_return(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    if !parser.test_kind(TokenKind::Return) {
+        return false;
+    }
+    let t = parser.next().unwrap().clone();
+    let mut node = AstNode::new_pos(AstKind::Return, t.pos);
+    if is_stack_top_josi(parser, &["で"]) {
+        let mut value = parser.stack.pop().unwrap();
+        value.josi = None;
+        node.add_child(value);
+    }
+    parent.add_child(node);
+    true
+}
+
+/// 「それ = (値)」の代入ノードを作る
+fn new_let_sore(mut value: AstNode) -> AstNode {
+    value.josi = None;
+    let mut var_node = AstNode::new_pos(AstKind::Nop, value.pos);
+    var_node.value = crate::value::Value::from_str("それ");
+    let mut let_node = AstNode::new_pos(AstKind::Let, value.pos);
+    let_node.add_child(var_node);
+    let_node.add_child(value);
+    let_node
+}
+
+/// 「抜ける」「続ける」の解析
+fn parse_break_continue(parser: &mut Parser, parent: &mut AstNode) -> bool {
+    let t = parser.next().unwrap();
+    let kind = if t.kind == TokenKind::Break { AstKind::Break } else { AstKind::Continue };
+    let node = AstNode::new_pos(kind, t.pos);
+    parent.add_child(node);
+    true
+}
+
+/// 繰り返しや関数定義など、構文の本体の解析
+/// 直後が改行なら「ここまで」までをブロックとして読み、そうでなければ一文を読む
+fn parse_block_body(parser: &mut Parser, body: &mut AstNode, start_t: &Token) {
+    if !parser.test_kind(TokenKind::EOS) {
+        parse_sentence(parser, body);
+        return;
+    }
+    parse_block(parser, body, &[TokenKind::BlockEnd]);
+    if parser.test_kind(TokenKind::BlockEnd) {
+        parser.next();
+    } else {
+        parser.error_at(ErrorCode::MissingBlockEnd, start_t,
+            &format!("『{}』に対応する『ここまで』がありません", start_t.value.as_deref().unwrap_or("")));
+    }
+}
+
+/// 条件式の解析 --- 「A=5ならば」「Aが5ならば」「Aが5以上ならば」の形
+fn parse_condition(parser: &mut Parser, if_t: &Token) -> Option<AstNode> {
+    let base = parser.stack.len();
+    while parse_value(parser) {
+        if is_stack_top_josi(parser, &["ならば", "なら"]) {
+            break;
+        }
+    }
+    if !is_stack_top_josi(parser, &["ならば", "なら"]) {
+        parser.error_at(ErrorCode::InvalidCondition, if_t, "『もし』の条件に『ならば』がありません");
+        parser.stack.truncate(base);
+        return None;
+    }
+    let args = parser.stack.split_off(base);
+    match build_condition(args, if_t) {
+        Ok(node) => Some(node),
+        Err(err) => {
+            // 本体は読み進めるため、条件は空のノードにしておく
+            parser.errors.push(err);
+            Some(AstNode::new_nop())
+        }
+    }
+}
+
+/// 条件の値の並びから条件式のノードを組み立てる
+/// [A] / [Aが, B] / [Aが, B, 以上] の形に対応する
+fn build_condition(mut args: Vec<AstNode>, t: &Token) -> Result<AstNode, NakoError> {
+    match args.len() {
+        1 => Ok(args.pop().unwrap()),
+        2 => {
+            // 「AがBならば」は「A=Bならば」と同じ
+            let right = args.pop().unwrap();
+            let left = args.pop().unwrap();
+            if !matches!(left.josi.as_deref(), Some("が") | Some("は")) {
+                return Err(NakoError::parser(ErrorCode::InvalidCondition, left.pos, "条件式の助詞が不正です"));
+            }
+            let mut eq_node = AstNode::new_pos(AstKind::Eq, left.pos);
+            eq_node.add_child(left);
+            eq_node.add_child(right);
+            Ok(eq_node)
+        },
+        3 => {
+            // 「AがB以上ならば」の形
+            let word = args.pop().unwrap();
+            let mut right = args.pop().unwrap();
+            let left = args.pop().unwrap();
+            let kind = if word.kind == AstKind::Variable {
+                get_compare_word_kind(&word.value.to_string())
+            } else {
+                None
+            };
+            let Some(kind) = kind else {
+                return Err(NakoError::parser(ErrorCode::InvalidCondition, word.pos, "条件式の比較語が不正です"));
+            };
+            if !matches!(left.josi.as_deref(), Some("が") | Some("は")) || right.josi.is_some() {
+                return Err(NakoError::parser(ErrorCode::InvalidCondition, left.pos, "条件式の助詞が不正です"));
+            }
+            right.josi = None;
+            let mut cmp_node = AstNode::new_pos(kind, left.pos);
+            cmp_node.add_child(left);
+            cmp_node.add_child(right);
+            Ok(cmp_node)
+        },
+        _ => Err(NakoError::parser(ErrorCode::InvalidCondition, t.pos, "条件式が不正です")),
+    }
+}
+
+/// 比較語(以上/以下/未満/超)に対応する比較演算のノード種別を返す
+fn get_compare_word_kind(word: &str) -> Option<AstKind> {
+    match word {
+        "以上" => Some(AstKind::GtEq),
+        "以下" => Some(AstKind::LtEq),
+        "未満" => Some(AstKind::Lt),
+        "超" => Some(AstKind::Gt),
+        _ => None,
+    }
+}
+
+/// スタック最上位の値の助詞が指定のいずれかかを判定する
+fn is_stack_top_josi(parser: &Parser, josi_list: &[&str]) -> bool {
+    if let Some(node) = parser.stack.last()
+        && let Some(ref josi) = node.josi {
+        return josi_list.contains(&josi.as_str());
+    }
+    false
+}
+
 fn parse_value(parser: &mut Parser) -> bool {
     if let Some(t) = parser.peek() {
         let kind = t.kind;  // Copy trait なのでコストゼロ
@@ -181,13 +767,15 @@ fn parse_value(parser: &mut Parser) -> bool {
             TokenKind::Str => parse_str(parser),
             TokenKind::Word => parse_word(parser),
             TokenKind::ParenL => parse_parenthesis(parser),
+            TokenKind::BracketL => parse_array(parser),
             _ => false,
         };
     }
     false
 }
 
-fn parse_nop(_parser: &mut Parser, _parent: &mut AstNode) -> bool {
+fn parse_nop(parser: &mut Parser, _parent: &mut AstNode) -> bool {
+    parser.next();
     true
 }
 
@@ -209,7 +797,7 @@ fn parse_number(parser: &mut Parser) -> bool {
         let has_josi = push_value_to_stack(parser, &token);
         // 助詞がない場合のみ、次のトークンをpeek()して演算子なら処理
         if !has_josi {
-            process_operators(parser);
+            process_operators(parser, 0);
         }
         return true;
     }
@@ -222,7 +810,7 @@ fn parse_str(parser: &mut Parser) -> bool {
         let has_josi = push_value_to_stack(parser, &token);
         // 助詞がない場合のみ、次のトークンをpeek()して演算子なら処理
         if !has_josi {
-            process_operators(parser);
+            process_operators(parser, 0);
         }
         return true;
     }
@@ -232,31 +820,21 @@ fn parse_str(parser: &mut Parser) -> bool {
 fn parse_word(parser: &mut Parser) -> bool {
     if let Some(token) = parser.next() {
         let token = token.clone();
+        // 関数呼び出し
+        if let Some(ref name) = token.value
+            && parser.funcs.contains_key(name) {
+            return parse_call(parser, &token);
+        }
         let has_josi = push_value_to_stack(parser, &token);
         // 助詞がない場合のみ、次のトークンをpeek()して演算子なら処理
         if !has_josi {
-            process_operators(parser);
+            process_operators(parser, 0);
         }
         return true;
     }
     false
 }
 
-fn parse_print(parser: &mut Parser, parent: &mut AstNode) -> bool {
-    if parser.test_kind(TokenKind::Print) == false {
-        return false;
-    }
-    let t = parser.next().unwrap();
-    let mut node = AstNode::new_pos(AstKind::Print, t.pos);
-    if let Some(arg) = parser.stack.pop() {
-        node.add_child(arg);
-    } else {
-        node.add_child(AstNode::new_nop());
-    }
-    parent.add_child(node);
-    true
-}
-
 fn parse_eos(parser: &mut Parser, parent: &mut AstNode) -> bool {
     if let Some(t) = parser.next() {
         let node = AstNode::new_pos(AstKind::EOS, t.pos);
@@ -267,58 +845,75 @@ fn parse_eos(parser: &mut Parser, parent: &mut AstNode) -> bool {
 }
 
 fn parse_parenthesis(parser: &mut Parser) -> bool {
+    if !read_parenthesis(parser) {
+        return false;
+    }
+    // 助詞がない場合のみ、括弧の後の演算子を処理
+    if !is_stack_top_josi_any(parser) {
+        process_operators(parser, 0);
+    }
+    true
+}
+
+/// 括弧内の式を読んでスタックに積む
+fn read_parenthesis(parser: &mut Parser) -> bool {
     let start_token = parser.next().unwrap().clone();
-    // 括弧内の式を解析するために一時的なトークン列を収集
-    let mut paren_tokens = Vec::new();
-    let mut paren_level = 1;
-    
-    while let Some(token) = parser.next() {
-        let token = token.clone();
-        match token.kind {
-            TokenKind::ParenL => {
-                paren_level += 1;
-                paren_tokens.push(token);
-            },
-            TokenKind::ParenR => {
-                paren_level -= 1;
-                if paren_level == 0 {
-                    // 括弧を閉じたので、括弧内の式を解析
-                    let mut sub_parser = Parser::new(paren_tokens);
-                    let mut dummy_parent = AstNode::new(AstKind::Node);
-                    parse_sentence(&mut sub_parser, &mut dummy_parent);
-                    
-                    // 括弧内の式の結果をスタックに移す
-                    if let Some(result) = sub_parser.stack.pop() {
-                        parser.stack.push(result);
-                    } else if let Some(children) = dummy_parent.children {
-                        // スタックが空の場合、親ノードの最初の子を使う
-                        if !children.is_empty() {
-                            parser.stack.push(children[0].clone());
-                        }
-                    }
-                    
-                    // 括弧の後に演算子がある場合の処理
-                    // 次のトークンをチェック
-                    if let Some(next_token) = parser.peek() {
-                        if next_token.kind.is_operator() {
-                            process_operators(parser);
-                        }
-                    }
-                    
-                    return false;
-                }
-                paren_tokens.push(token);
-            },
-            _ => {
-                paren_tokens.push(token);
-            }
-        }
+    let base = parser.stack.len();
+    while parse_value(parser) {
+        // 括弧内の値を読む
     }
-    
     // 括弧が閉じられなかった場合のエラー
-    println!("[ERROR][Parser] Unmatched parenthesis at {}:{}", 
-        start_token.pos.line, start_token.pos.column);
-    false
+    if !parser.test_kind(TokenKind::ParenR) || parser.stack.len() != base + 1 {
+        parser.error_at(ErrorCode::UnmatchedParen, &start_token, "括弧『(』が閉じられていません");
+        parser.stack.truncate(base);
+        return false;
+    }
+    let end_token = parser.next().unwrap().clone();
+    // 閉じ括弧の助詞を括弧全体の助詞とする
+    let mut result = parser.stack.pop().unwrap();
+    result.josi = end_token.josi.clone();
+    parser.stack.push(result);
+    true
+}
+
+fn parse_array(parser: &mut Parser) -> bool {
+    if !read_array(parser) {
+        return false;
+    }
+    // 助詞がない場合のみ、配列の後の演算子を処理
+    if !is_stack_top_josi_any(parser) {
+        process_operators(parser, 0);
+    }
+    true
+}
+
+/// 配列リテラル「[A, B, C]」を読んでスタックに積む
+fn read_array(parser: &mut Parser) -> bool {
+    let start_token = parser.next().unwrap().clone();
+    let base = parser.stack.len();
+    loop {
+        // 要素の区切り(カンマと改行)は読み飛ばす
+        while parser.test_kinds(&[TokenKind::Comma, TokenKind::EOS]) {
+            parser.next();
+        }
+        if parser.test_kind(TokenKind::BracketR) {
+            break;
+        }
+        if !parse_value(parser) {
+            parser.error_at(ErrorCode::UnmatchedBracket, &start_token, "括弧『[』が閉じられていません");
+            parser.stack.truncate(base);
+            return false;
+        }
+    }
+    let end_token = parser.next().unwrap().clone();
+    let mut node = AstNode::new_pos(AstKind::Array, start_token.pos);
+    for mut item in parser.stack.split_off(base) {
+        item.josi = None;
+        node.add_child(item);
+    }
+    node.josi = end_token.josi.clone();
+    parser.stack.push(node);
+    true
 }
 
 /// 値をスタックに積む共通処理
@@ -330,15 +925,16 @@ fn push_value_to_stack(parser: &mut Parser, token: &Token) -> bool {
     match token.kind {
         TokenKind::Number => {
             let mut node = AstNode::new_pos(AstKind::Number, pos);
-            if let Some(ref val_str) = token.value {
-                if let Ok(num) = val_str.parse::<f64>() {
-                    node.value = crate::value::Value::from_number(num);
-                }
+            node.josi = token.josi.clone();
+            if let Some(ref val_str) = token.value
+                && let Ok(num) = val_str.parse::<f64>() {
+                node.value = crate::value::Value::from_number(num);
             }
             parser.stack.push(node);
         },
         TokenKind::Str => {
             let mut node = AstNode::new_pos(AstKind::String, pos);
+            node.josi = token.josi.clone();
             if let Some(ref val) = token.value {
                 node.value = crate::value::Value::from_string(val.clone());
             }
@@ -346,13 +942,15 @@ fn push_value_to_stack(parser: &mut Parser, token: &Token) -> bool {
         },
         TokenKind::Word => {
             let mut node = AstNode::new_pos(AstKind::Variable, pos);
+            node.josi = token.josi.clone();
             if let Some(ref val) = token.value {
                 node.value = crate::value::Value::from_string(val.clone());
             }
             parser.stack.push(node);
         },
         _ => {
-            println!("[ERROR][Parser] Cannot push non-value token to stack: {:?}", token);
+            parser.error_at(ErrorCode::ExpectedExpression, token,
+                &format!("『{}』は値ではありません", token.value.as_deref().unwrap_or("")));
             return false;
         }
     }
@@ -363,63 +961,81 @@ fn push_value_to_stack(parser: &mut Parser, token: &Token) -> bool {
 /// 演算子の優先順位を返す（数値が大きいほど優先度が高い）
 fn get_operator_precedence(kind: TokenKind) -> i32 {
     match kind {
-        TokenKind::Mul | TokenKind::Div => 2,
-        TokenKind::Plus | TokenKind::Minus => 1,
+        TokenKind::Mul | TokenKind::Div => 3,
+        TokenKind::Plus | TokenKind::Minus => 2,
+        TokenKind::Eq | TokenKind::EqEq | TokenKind::NotEq |
+        TokenKind::Gt | TokenKind::GtEq |
+        TokenKind::Lt | TokenKind::LtEq => 1,
         _ => 0,
     }
 }
 
+/// スタック最上位の値に助詞があるか
+fn is_stack_top_josi_any(parser: &Parser) -> bool {
+    parser.stack.last().is_some_and(|node| node.josi.is_some())
+}
+
 /// 次のトークンをpeek()して演算子なら優先順位を考慮して処理
-fn process_operators(parser: &mut Parser) {
+/// min_precedenceより優先順位の高い演算子だけを処理する
+/// 戻り値: 助詞のある値で式が終わった場合はtrue
+fn process_operators(parser: &mut Parser, min_precedence: i32) -> bool {
     while let Some(next_token) = parser.peek() {
         if !next_token.kind.is_operator() {
             break;
         }
-        
+        let op_precedence = get_operator_precedence(next_token.kind);
+        if op_precedence <= min_precedence {
+            break;
+        }
         let op_token = parser.next().unwrap().clone();
-        let op_precedence = get_operator_precedence(op_token.kind);
         
-        // 次の値を読む - 括弧の場合は parse_value で処理
+        // 次の値を読む - 括弧の場合は read_parenthesis で処理
         if let Some(next_value_token) = parser.peek() {
             if next_value_token.kind == TokenKind::ParenL {
                 // 括弧を処理
-                parse_value(parser);
-            } else {
-                // 通常の値を読む
-                if let Some(token) = parser.next() {
-                    let token = token.clone();
-                    let has_josi = push_value_to_stack(parser, &token);
-                    
-                    // 助詞がある場合は、これ以上演算子を処理せずに現在の演算子を処理して終了
-                    if has_josi {
-                        process_single_operator(parser, &op_token);
-                        return;
-                    }
-                } else {
-                    return;
+                if !read_parenthesis(parser) {
+                    return false;
                 }
+            } else if next_value_token.kind == TokenKind::BracketL {
+                // 配列リテラルを処理
+                if !read_array(parser) {
+                    return false;
+                }
+            } else if matches!(next_value_token.kind, TokenKind::Number | TokenKind::Str | TokenKind::Word) {
+                // 通常の値を読む
+                let token = parser.next().unwrap().clone();
+                push_value_to_stack(parser, &token);
+            } else {
+                parser.error_at(ErrorCode::ExpectedExpression, &op_token, "演算子の後に値がありません");
+                return false;
+            }
+            // 助詞がある場合は、これ以上演算子を処理せずに現在の演算子を処理して終了
+            if is_stack_top_josi_any(parser) {
+                process_single_operator(parser, &op_token);
+                return true;
             }
             
             // さらに次の演算子をチェックして優先順位を比較
-            if let Some(next_op_token) = parser.peek() {
-                if next_op_token.kind.is_operator() {
-                    let next_op_precedence = get_operator_precedence(next_op_token.kind);
-                    
-                    // 次の演算子の方が優先順位が高い場合は、先に処理
-                    if next_op_precedence > op_precedence {
-                        process_operators(parser);
-                    }
+            if let Some(next_op_token) = parser.peek()
+                && next_op_token.kind.is_operator() {
+                let next_op_precedence = get_operator_precedence(next_op_token.kind);
+                
+                // 次の演算子の方が優先順位が高い場合は、先に処理
+                if next_op_precedence > op_precedence
+                    && process_operators(parser, op_precedence) {
+                    process_single_operator(parser, &op_token);
+                    return true;
                 }
             }
         } else {
-            println!("[ERROR][Parser] Expected value after operator at {}:{}", 
-                op_token.pos.line, op_token.pos.column);
-            return;
+            parser.error_at(ErrorCode::ExpectedExpression, &op_token, "演算子の後に値がありません");
+            return false;
         }
         
         // 現在の演算子を処理
         process_single_operator(parser, &op_token);
     }
+    false
 }
 
 /// 単一の演算子を処理する（スタックから2つの値を取り出して演算ノードを作成）
@@ -429,7 +1045,7 @@ fn process_single_operator(parser: &mut Parser, op_token: &Token) {
     let left = parser.stack.pop();
     
     if left.is_none() || right.is_none() {
-        println!("[ERROR][Parser] Operator requires two operands at {}:{}", pos.line, pos.column);
+        parser.error(ErrorCode::MissingOperand, pos, "演算子の左右に値が必要です");
         return;
     }
     
@@ -438,15 +1054,25 @@ fn process_single_operator(parser: &mut Parser, op_token: &Token) {
         TokenKind::Minus => AstKind::Minus,
         TokenKind::Mul => AstKind::Mul,
         TokenKind::Div => AstKind::Div,
+        TokenKind::Eq | TokenKind::EqEq => AstKind::Eq,
+        TokenKind::NotEq => AstKind::NotEq,
+        TokenKind::Gt => AstKind::Gt,
+        TokenKind::GtEq => AstKind::GtEq,
+        TokenKind::Lt => AstKind::Lt,
+        TokenKind::LtEq => AstKind::LtEq,
         _ => {
-            println!("[ERROR][Parser] Unknown operator: {:?}", op_token.kind);
+            parser.error(ErrorCode::UnknownToken, pos,
+                &format!("『{}』は演算子ではありません", op_token.value.as_deref().unwrap_or("")));
             return;
         }
     };
     
     let mut op_node = AstNode::new_pos(ast_kind, pos);
+    let mut right = right.unwrap();
+    // 右辺の助詞を式全体の助詞とする
+    op_node.josi = right.josi.take();
     op_node.add_child(left.unwrap());
-    op_node.add_child(right.unwrap());
+    op_node.add_child(right);
     
     parser.stack.push(op_node);
 }
@@ -457,7 +1083,7 @@ mod tests {
     use crate::source::SourcePos;
     use crate::value::Value;
 
-    /// Ensures a simple expression is parsed into a Print node with a Plus child
+    /// Ensures 「3+5を表示」 is parsed into a call of 表示 with a Plus argument
     #[test]
     fn parse_simple_expression() {
         let pos = SourcePos::zero();
@@ -465,20 +1091,23 @@ mod tests {
             Token::new(TokenKind::Number, Some("3".to_string()), pos),
             Token::new(TokenKind::Plus, None, pos),
             Token::new_arg(TokenKind::Number, "5", "を", pos),
-            Token::new(TokenKind::Print, None, pos),
+            Token::new(TokenKind::Word, Some("表示".to_string()), pos),
             Token::new(TokenKind::EOS, None, pos),
         ];
 
-        let ast = parse(tokens);
+        let ast = parse(tokens).unwrap();
 
         let root_children = ast.children.as_ref().expect("root should have children");
-        assert!(root_children.len() >= 1, "root must contain one statement");
+        assert!(!root_children.is_empty(), "root must contain one statement");
 
-        let print_node = &root_children[0];
-        assert_eq!(print_node.kind, AstKind::Print);
+        let let_node = &root_children[0];
+        assert_eq!(let_node.kind, AstKind::Let);
+        let print_node = &let_node.children.as_ref().unwrap()[1];
+        assert_eq!(print_node.kind, AstKind::Call);
+        assert_eq!(print_node.value, Value::from_str("表示"));
 
         let print_args = print_node.children.as_ref().expect("print should have an argument");
-        assert!(print_args.len() >= 1, "print should have one argument");
+        assert!(!print_args.is_empty(), "print should have one argument");
 
         let plus_node = &print_args[0];
         assert_eq!(plus_node.kind, AstKind::Plus);
@@ -488,5 +1117,71 @@ mod tests {
         assert_eq!(operands[0].value, Value::from_number(3.0));
         assert_eq!(operands[1].value, Value::from_number(5.0));
     }
-}
 
+    /// Ensures 「もしAが5ならば」 is parsed into an If node with an Eq condition
+    #[test]
+    fn parse_if_with_josi_condition() {
+        let pos = SourcePos::zero();
+        let tokens = vec![
+            Token::new(TokenKind::If, None, pos),
+            Token::new_arg(TokenKind::Word, "A", "が", pos),
+            Token::new_arg(TokenKind::Number, "5", "ならば", pos),
+            Token::new_arg(TokenKind::Str, "OK", "を", pos),
+            Token::new(TokenKind::Word, Some("表示".to_string()), pos),
+            Token::new(TokenKind::EOS, None, pos),
+        ];
+
+        let ast = parse(tokens).unwrap();
+
+        let root_children = ast.children.as_ref().expect("root should have children");
+        let if_node = &root_children[0];
+        assert_eq!(if_node.kind, AstKind::If);
+
+        let if_children = if_node.children.as_ref().expect("if should have children");
+        assert_eq!(if_children.len(), 3);
+        assert_eq!(if_children[0].kind, AstKind::Eq);
+        let then_children = if_children[1].children.as_ref().expect("then block should have a statement");
+        let call_node = &then_children[0].children.as_ref().unwrap()[1];
+        assert_eq!(call_node.kind, AstKind::Call);
+    }
+
+    /// Ensures call arguments are matched by josi, not by position
+    #[test]
+    fn parse_call_matches_josi() {
+        let pos = SourcePos::zero();
+        let tokens = vec![
+            Token::new_arg(TokenKind::Number, "5", "を", pos),
+            Token::new_arg(TokenKind::Number, "3", "と", pos),
+            Token::new(TokenKind::Word, Some("足算".to_string()), pos),
+        ];
+        let mut parser = Parser::new(tokens);
+        parser.funcs.insert("足算".to_string(), vec![vec!["と".to_string()], vec!["を".to_string()]]);
+        let mut root = AstNode::new(AstKind::Node);
+        parse_sentences(&mut parser, &mut root);
+
+        let root_children = root.children.as_ref().expect("root should have children");
+        let let_node = &root_children[0];
+        assert_eq!(let_node.kind, AstKind::Let);
+        let call_node = &let_node.children.as_ref().unwrap()[1];
+        assert_eq!(call_node.kind, AstKind::Call);
+        let args = call_node.children.as_ref().expect("call should have arguments");
+        assert_eq!(args[0].value, Value::from_number(3.0));
+        assert_eq!(args[1].value, Value::from_number(5.0));
+    }
+
+    /// Ensures every syntax error is reported and parsing resumes at the next statement
+    #[test]
+    fn parse_reports_all_errors() {
+        let src = "A=\n「ok」を表示\n回\n  1を表示\nここまで\nB=(1+2\n「ok」を表示";
+        let tokens = crate::lexer::lex(&mut crate::source::Source::new(src)).unwrap();
+        let err = parse(tokens).unwrap_err();
+        let codes: Vec<ErrorCode> = err.errors().iter().map(|e| e.code()).collect();
+        assert_eq!(codes, vec![
+            ErrorCode::ExpectedExpression,
+            ErrorCode::MissingTimesCount,
+            ErrorCode::UnmatchedParen,
+        ]);
+        let lines: Vec<usize> = err.errors().iter().map(|e| e.pos().line).collect();
+        assert_eq!(lines, vec![0, 2, 5]);
+    }
+}
@@ -0,0 +1,426 @@
+//! regvm module
+//! Register-based VM, the other backend to the stack VM in `vm.rs` (selected with `-R`).
+//!
+//! The register codes are translated from the verified stack codes that `ast_to_bytecode`
+//! made from the AST, so both backends share the parser, the compiler and the optimizer.
+//! The stack slot at depth `d` becomes register `d` of the running function, and constants and
+//! variables are read in place by the codes using them instead of being pushed as copies.
+//! Rare codes (arrays, `Call`, ...) and every failing case run through the stack VM,
+//! so the output and the errors are the same on both backends.
+
+use crate::bytecode::{ByteCode, ByteCodeKind, NakoSystem, Operand};
+use crate::error::NakoError;
+use crate::value::Value;
+use crate::{verify, vm};
+
+/// Where a code reads a value from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Src {
+    Reg(usize),
+    Var(usize),
+    Const(usize),
+}
+
+/// Where a code writes a value to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dst {
+    Reg(usize),
+    Var(usize),
+}
+
+/// Operation of a register code (addresses are indexes of the register codes)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegOp {
+    /// set the line of the source (EOS)
+    Line(usize),
+    Move { dst: Dst, src: Src },
+    Binary { kind: ByteCodeKind, dst: Dst, left: Src, right: Src },
+    Jump(usize),
+    JumpIf { when: bool, cond: Src, addr: usize },
+    /// counter += 1, and jump to addr if counter > limit
+    CountUp { counter: usize, limit: usize, addr: usize },
+    /// run the stack code with `pops` registers from `base` as its stack, and put the results there
+    Stack { base: usize, pops: usize },
+    Return(Src),
+}
+
+/// Register code with the index of the stack code it was made from (for errors and fallbacks)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegCode {
+    pub op: RegOp,
+    pub origin: usize,
+}
+
+/// Register codes translated from the stack codes of a system
+#[derive(Clone, Debug, Default)]
+pub struct RegProgram {
+    pub codes: Vec<RegCode>,
+    /// index of the register code for each stack code (and for the end)
+    pub addr_of: Vec<usize>,
+    /// number of registers used by the main program or a function
+    pub frame_size: usize,
+}
+
+/// Run the system on the register VM
+pub fn run(sys: &mut NakoSystem) -> Result<(), NakoError> {
+    run_from(sys, 0)
+}
+
+/// Run the system on the register VM from the given stack code index (see `vm::run_from`)
+pub fn run_from(sys: &mut NakoSystem, start: usize) -> Result<(), NakoError> {
+    // the debug output is printed by the stack VM
+    if sys.is_debug {
+        return vm::run_from(sys, start);
+    }
+    verify::verify_more(sys)?;
+    let program = translate(sys, start)?;
+    let result = exec(sys, &program, start);
+    sys.output.flush();
+    result
+}
+
+/// Translate the verified stack codes into register codes.
+/// `start` is where the run begins, which must be the start of a block.
+pub fn translate(sys: &NakoSystem, start: usize) -> Result<RegProgram, NakoError> {
+    let depths = verify::stack_depths(sys)?;
+    let len = sys.codes.len();
+    // codes reached from other places than the previous code
+    let mut block_start = vec![false; len + 1];
+    block_start[0] = true;
+    block_start[start.min(len)] = true;
+    for code in &sys.codes {
+        for (op, arg) in code.kind.operands().iter().zip([code.arg1, code.arg2, code.arg3]) {
+            if *op == Operand::Addr && arg <= len {
+                block_start[arg] = true;
+            }
+        }
+    }
+    for func in sys.func_table.funcs.iter().filter(|func| func.sys_func.is_none()) {
+        block_start[func.addr] = true;
+    }
+    let mut tr = Translator::default();
+    let mut addr_of = vec![0; len + 1];
+    let mut live = false;
+    for pc in 0..len {
+        if block_start[pc] || !live {
+            // values on the stack are in their own registers at the start of a block
+            if live {
+                tr.flush(pc);
+            }
+            addr_of[pc] = tr.codes.len();
+            tr.last_binary = None;
+            match depths[pc] {
+                Some(depth) => tr.vstack = (0..depth).map(Src::Reg).collect(),
+                None => {
+                    live = false;
+                    continue;
+                }
+            }
+        } else {
+            addr_of[pc] = tr.codes.len();
+        }
+        live = tr.translate_code(sys.codes[pc], pc);
+    }
+    addr_of[len] = tr.codes.len();
+    // jump targets were indexes of the stack codes
+    for code in tr.codes.iter_mut() {
+        match &mut code.op {
+            RegOp::Jump(addr) | RegOp::JumpIf { addr, .. } | RegOp::CountUp { addr, .. } => *addr = addr_of[*addr],
+            _ => {},
+        }
+    }
+    let frame_size = depths.iter().flatten().copied().max().unwrap_or(0) + 1;
+    Ok(RegProgram { codes: tr.codes, addr_of, frame_size })
+}
+
+/// State of the translation: the codes made so far and where each value of the stack is
+#[derive(Default)]
+struct Translator {
+    codes: Vec<RegCode>,
+    /// source of each value on the stack (a pushed value stays a constant or a variable until needed)
+    vstack: Vec<Src>,
+    /// the last code is a Binary whose result can be written to a variable directly
+    last_binary: Option<usize>,
+}
+impl Translator {
+    fn emit(&mut self, origin: usize, op: RegOp) {
+        self.codes.push(RegCode { op, origin });
+        self.last_binary = None;
+    }
+
+    /// Translate one stack code, and return false if the next code is not reached from it
+    fn translate_code(&mut self, code: ByteCode, pc: usize) -> bool {
+        let depth = self.vstack.len();
+        match code.kind {
+            ByteCodeKind::Nop => {},
+            ByteCodeKind::EOS => self.emit(pc, RegOp::Line(code.arg1)),
+            ByteCodeKind::PushConst => self.vstack.push(Src::Const(code.arg1)),
+            ByteCodeKind::PushVariable => self.vstack.push(Src::Var(code.arg1)),
+            ByteCodeKind::Let => {
+                let src = self.pop();
+                self.store(pc, code.arg1, src);
+            },
+            ByteCodeKind::Add | ByteCodeKind::Sub | ByteCodeKind::Mul | ByteCodeKind::Div
+            | ByteCodeKind::Eq | ByteCodeKind::NotEq | ByteCodeKind::Gt | ByteCodeKind::GtEq
+            | ByteCodeKind::Lt | ByteCodeKind::LtEq => {
+                let right = self.pop();
+                let left = self.pop();
+                let dst = depth - 2;
+                self.emit(pc, RegOp::Binary { kind: code.kind, dst: Dst::Reg(dst), left, right });
+                self.last_binary = Some(self.codes.len() - 1);
+                self.vstack.push(Src::Reg(dst));
+            },
+            ByteCodeKind::Jump => {
+                self.flush(pc);
+                self.emit(pc, RegOp::Jump(code.arg1));
+                return false;
+            },
+            ByteCodeKind::JumpIfFalse | ByteCodeKind::JumpIfTrue => {
+                let cond = self.pop();
+                self.flush(pc);
+                let when = code.kind == ByteCodeKind::JumpIfTrue;
+                self.emit(pc, RegOp::JumpIf { when, cond, addr: code.arg1 });
+            },
+            ByteCodeKind::Pop => {
+                self.pop();
+            },
+            ByteCodeKind::CountUp => {
+                self.flush(pc);
+                self.emit(pc, RegOp::CountUp { counter: code.arg1, limit: code.arg2, addr: code.arg3 });
+            },
+            ByteCodeKind::Return => {
+                let src = self.pop();
+                self.emit(pc, RegOp::Return(src));
+                return false;
+            },
+            ByteCodeKind::ForStep | ByteCodeKind::ForCheck | ByteCodeKind::MakeArray
+            | ByteCodeKind::ForeachNext | ByteCodeKind::Call => {
+                // these may change variables, so every value is put in its register first
+                self.flush(pc);
+                let (pops, pushes) = verify::stack_effect(&code);
+                let base = depth - pops;
+                self.emit(pc, RegOp::Stack { base, pops });
+                self.vstack.truncate(base);
+                self.vstack.extend((base..base + pushes).map(Src::Reg));
+            },
+        }
+        true
+    }
+
+    fn pop(&mut self) -> Src {
+        // the verifier has checked the stack depth
+        self.vstack.pop().unwrap_or(Src::Const(0))
+    }
+
+    /// Write the value to the variable
+    fn store(&mut self, pc: usize, var: usize, src: Src) {
+        // the old value of the variable may still be waiting on the stack
+        for (index, entry) in self.vstack.iter_mut().enumerate() {
+            if *entry == Src::Var(var) {
+                *entry = Src::Reg(index);
+                self.codes.push(RegCode { op: RegOp::Move { dst: Dst::Reg(index), src: Src::Var(var) }, origin: pc });
+                self.last_binary = None;
+            }
+        }
+        // `A=A+1` becomes one Binary writing to A
+        if let Some(index) = self.last_binary
+            && let RegOp::Binary { ref mut dst, .. } = self.codes[index].op
+            && Src::Reg(match *dst { Dst::Reg(reg) => reg, Dst::Var(_) => usize::MAX }) == src {
+            *dst = Dst::Var(var);
+            self.last_binary = None;
+            return;
+        }
+        self.emit(pc, RegOp::Move { dst: Dst::Var(var), src });
+    }
+
+    /// Put every value on the stack in its own register
+    fn flush(&mut self, pc: usize) {
+        for index in 0..self.vstack.len() {
+            let src = self.vstack[index];
+            if src != Src::Reg(index) {
+                self.emit(pc, RegOp::Move { dst: Dst::Reg(index), src });
+                self.vstack[index] = Src::Reg(index);
+            }
+        }
+    }
+}
+
+/// Return address of a running function
+struct Frame {
+    return_pc: usize,
+    base: usize,
+    /// register of the caller for the returned value
+    dst: usize,
+}
+
+/// Execute the register codes from the given stack code index until the end or an error
+fn exec(sys: &mut NakoSystem, program: &RegProgram, start: usize) -> Result<(), NakoError> {
+    sys.frames.clear();
+    let mut regs = vec![Value::None; program.frame_size];
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut base = 0;
+    let mut pc = program.addr_of[start];
+    while let Some(code) = program.codes.get(pc) {
+        pc += 1;
+        match code.op {
+            RegOp::Line(line) => sys.src_lineno = line,
+            RegOp::Move { dst, src } => {
+                let value = take(sys, &mut regs, base, src);
+                write(sys, &mut regs, base, dst, value);
+            },
+            RegOp::Binary { kind, dst, left, right } => {
+                let result = {
+                    let (l, r) = (read(sys, &regs, base, left), read(sys, &regs, base, right));
+                    match (l, r) {
+                        (Value::Number(l), Value::Number(r)) => vm::number_op(kind, *l, *r).map(Ok),
+                        _ => None,
+                    }.unwrap_or_else(|| vm::binary_op(kind, l, r))
+                };
+                match result {
+                    Ok(value) => write(sys, &mut regs, base, dst, value),
+                    Err((err_code, msg)) => {
+                        sys.pc = code.origin + 1;
+                        sys.runtime_error(err_code, msg);
+                        return Err(vm::last_error(sys));
+                    }
+                }
+            },
+            RegOp::Jump(addr) => pc = addr,
+            RegOp::JumpIf { when, cond, addr } => {
+                if read(sys, &regs, base, cond).to_bool() == when {
+                    pc = addr;
+                }
+            },
+            RegOp::CountUp { counter, limit, addr } => {
+                if let (Some(Value::Number(n)), Some(Value::Number(limit))) =
+                    (sys.var_table.get_by_index(counter), sys.var_table.get_by_index(limit)) {
+                    let (n, limit) = (n + 1.0, *limit);
+                    sys.var_table.set_by_index(counter, Value::Number(n));
+                    if n > limit {
+                        pc = addr;
+                    }
+                } else {
+                    exec_stack_code(sys, &mut regs, base, 0, code.origin)?;
+                    if sys.pc != code.origin + 1 {
+                        pc = program.addr_of[sys.pc];
+                    }
+                }
+            },
+            RegOp::Stack { base: offset, pops } => {
+                let depth = sys.frames.len();
+                exec_stack_code(sys, &mut regs, base + offset, pops, code.origin)?;
+                if sys.frames.len() > depth {
+                    // entered a user function: its registers follow the ones of the caller
+                    frames.push(Frame { return_pc: pc, base, dst: base + offset });
+                    base += offset;
+                    if regs.len() < base + program.frame_size {
+                        regs.resize(base + program.frame_size, Value::None);
+                    }
+                    pc = program.addr_of[sys.pc];
+                    continue;
+                }
+                for (index, value) in sys.stack.drain(..).enumerate() {
+                    regs[base + offset + index] = value;
+                }
+                if sys.pc != code.origin + 1 {
+                    pc = program.addr_of[sys.pc];
+                }
+            },
+            RegOp::Return(src) => {
+                let value = take(sys, &mut regs, base, src);
+                sys.stack.push(value);
+                // restores the local variables of the caller
+                exec_stack_code(sys, &mut regs, base, 0, code.origin)?;
+                let Some(frame) = frames.pop() else {
+                    return Err(vm::last_error(sys));
+                };
+                regs[frame.dst] = sys.stack.pop().unwrap_or(Value::None);
+                base = frame.base;
+                pc = frame.return_pc;
+            },
+        }
+    }
+    sys.pc = sys.codes.len();
+    Ok(())
+}
+
+/// Run the stack code at `origin` on the stack VM with `pops` registers from `base` as its stack
+fn exec_stack_code(sys: &mut NakoSystem, regs: &mut [Value], base: usize, pops: usize, origin: usize) -> Result<(), NakoError> {
+    for reg in regs[base..base + pops].iter_mut() {
+        sys.stack.push(std::mem::replace(reg, Value::None));
+    }
+    sys.pc = origin + 1;
+    let code = sys.codes[origin];
+    if !vm::exec_code(sys, &code) {
+        return Err(vm::last_error(sys));
+    }
+    Ok(())
+}
+
+fn read<'a>(sys: &'a NakoSystem, regs: &'a [Value], base: usize, src: Src) -> &'a Value {
+    match src {
+        Src::Reg(reg) => &regs[base + reg],
+        Src::Var(var) => sys.var_table.get_by_index(var).unwrap_or(&Value::None),
+        Src::Const(index) => sys.const_list.get(index).unwrap_or(&Value::None),
+    }
+}
+
+/// Read the value, moving it out of the register (each register value is read once)
+fn take(sys: &NakoSystem, regs: &mut [Value], base: usize, src: Src) -> Value {
+    match src {
+        Src::Reg(reg) => std::mem::replace(&mut regs[base + reg], Value::None),
+        _ => read(sys, regs, base, src).clone(),
+    }
+}
+
+fn write(sys: &mut NakoSystem, regs: &mut [Value], base: usize, dst: Dst, value: Value) {
+    match dst {
+        Dst::Reg(reg) => regs[base + reg] = value,
+        Dst::Var(var) => sys.var_table.set_by_index(var, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translated(code: &str) -> (NakoSystem, RegProgram) {
+        let sys = crate::compile(code, &crate::NakoOptions::new()).unwrap();
+        let program = translate(&sys, 0).unwrap();
+        (sys, program)
+    }
+
+    #[test]
+    fn let_writes_result_directly() {
+        // A=A+1 は変数を直接読み書きする1命令になる
+        let (sys, program) = translated("A=1\nA=A+1");
+        let a = sys.var_table.get_name_index("A").unwrap();
+        let binary: Vec<&RegOp> = program.codes.iter().map(|code| &code.op)
+            .filter(|op| matches!(op, RegOp::Binary { .. })).collect();
+        assert_eq!(binary.len(), 1);
+        assert!(matches!(binary[0], RegOp::Binary { dst: Dst::Var(var), left: Src::Var(left), right: Src::Const(_), .. }
+            if *var == a && *left == a));
+        assert!(!program.codes.iter().any(|code| matches!(code.op, RegOp::Stack { .. })));
+    }
+
+    #[test]
+    fn old_value_is_kept_before_let() {
+        // 先に積んだ変数の値は、その後の代入で変わらない
+        let text = "PushConst 1\nLet A\nPushVariable A\nPushConst 5\nLet A\nPushConst 1\nAdd\nLet B";
+        let mut sys = crate::asm::assemble(text).unwrap();
+        run(&mut sys).unwrap();
+        let b = sys.var_table.get_name_index("B").unwrap();
+        assert_eq!(sys.var_table.get_by_index(b), Some(&Value::from_number(2.0)));
+    }
+
+    #[test]
+    fn runtime_error_has_same_position() {
+        let code = "●(Nを)割るとは\n1/Nで戻る\nここまで\n「a」を表示\n0を割るを表示";
+        let mut stack = crate::compile(code, &crate::NakoOptions::new()).unwrap();
+        let mut register = stack.clone();
+        let err1 = vm::run(&mut stack).unwrap_err();
+        let err2 = run(&mut register).unwrap_err();
+        assert_eq!(err1.to_string(), err2.to_string());
+        assert_eq!(err1.trace(), err2.trace());
+    }
+}
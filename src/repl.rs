@@ -0,0 +1,193 @@
+//! repl module
+//! Evaluates the input line by line with a long-lived NakoSystem,
+//! so variables and functions stay defined for the following lines.
+
+use crate::ast::{AstKind, AstNode};
+use crate::bytecode::NakoSystem;
+use crate::error::{ErrorCode, NakoError};
+use crate::lexer;
+use crate::parser;
+use crate::source::Source;
+use crate::value::Value;
+use crate::{NakoOptions, asm, compile_ast_into, optimize, run_vm};
+
+/// Result of feeding one line to the REPL
+#[derive(Debug)]
+pub enum ReplStatus {
+    /// the input is not finished yet (an open block, string or array)
+    NeedMore,
+    /// the input was executed (the printed text went to the output of the system),
+    /// with the value of the bare expression to echo
+    Done { echo: Option<Value> },
+    /// the input has errors, `source` is the input for rendering the error
+    Error { error: NakoError, source: String },
+}
+
+/// REPL state
+pub struct Repl {
+    pub sys: NakoSystem,
+    /// options used to compile and run each input (-D, -O, -R)
+    pub options: NakoOptions,
+    /// lines of the unfinished input
+    pending: String,
+}
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Repl {
+    pub fn new() -> Self {
+        Self::with_system(NakoSystem::new())
+    }
+    /// Use the given system, such as one with host functions registered
+    pub fn with_system(sys: NakoSystem) -> Self {
+        Repl { sys, options: NakoOptions::new(), pending: String::new() }
+    }
+    /// Is there an unfinished input waiting for more lines?
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+    /// Drop the unfinished input
+    pub fn clear_pending(&mut self) {
+        self.pending.clear();
+    }
+    /// Feed one line. An empty line runs the unfinished input as it is.
+    pub fn feed_line(&mut self, line: &str) -> ReplStatus {
+        let force = line.trim().is_empty();
+        if force && self.pending.is_empty() {
+            return ReplStatus::Done { echo: None };
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        let source = self.pending.clone();
+        match self.eval(&source, !force) {
+            ReplStatus::NeedMore => ReplStatus::NeedMore,
+            status => {
+                self.pending.clear();
+                status
+            }
+        }
+    }
+    /// Compile the source after the codes already in the system and run only the new codes
+    fn eval(&mut self, source: &str, wait_more: bool) -> ReplStatus {
+        let error = |error: NakoError| ReplStatus::Error { error, source: source.to_string() };
+        let tokens = match lexer::lex(&mut Source::new(source)) {
+            Ok(tokens) => tokens,
+            Err(err) if wait_more && err.code() == ErrorCode::UnclosedString => return ReplStatus::NeedMore,
+            Err(err) => return error(err),
+        };
+        let ast = match parser::parse_with_funcs(tokens, &self.sys.func_table) {
+            Ok(ast) => ast,
+            Err(err) if wait_more && is_unfinished(&err) => return ReplStatus::NeedMore,
+            Err(err) => return error(err),
+        };
+        let start = match compile_ast_into(&mut self.sys, &ast, &self.options) {
+            Ok(start) => start,
+            Err(err) => return error(err),
+        };
+        if self.options.optimize {
+            optimize::optimize_from(&mut self.sys, start);
+        }
+        if self.options.is_debug {
+            eprint!("{}", asm::disassemble_from(&self.sys, start, Some(source)));
+        }
+        // run
+        let result = run_vm(&mut self.sys, start, &self.options);
+        if let Err(err) = result {
+            self.sys.stack.clear();
+            return error(err);
+        }
+        self.sys.stack.clear();
+        let echo = if is_bare_expression(&ast) { self.sore() } else { None };
+        ReplStatus::Done { echo }
+    }
+    /// Value of それ, if it has one
+    fn sore(&self) -> Option<Value> {
+        let index = self.sys.var_table.get_name_index("それ")?;
+        match self.sys.var_table.get_by_index(index) {
+            Some(Value::None) | None => None,
+            Some(value) => Some(value.clone()),
+        }
+    }
+}
+
+/// Does the input end in the middle of a block or an array?
+fn is_unfinished(err: &NakoError) -> bool {
+    err.errors().iter().any(|e| matches!(e.code(), ErrorCode::MissingBlockEnd | ErrorCode::UnmatchedBracket))
+}
+
+/// Is the last statement a bare expression, whose value was assigned to それ?
+fn is_bare_expression(ast: &AstNode) -> bool {
+    let Some(children) = ast.children.as_ref() else {
+        return false;
+    };
+    let last = children.iter().rev()
+        .find(|node| !matches!(node.kind, AstKind::EOS | AstKind::Comment | AstKind::Nop));
+    match last {
+        Some(node) if node.kind == AstKind::Let => node.children.as_ref()
+            .and_then(|c| c.first())
+            .is_some_and(|var| var.value.to_string() == "それ"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn done(status: ReplStatus) -> Option<String> {
+        match status {
+            ReplStatus::Done { echo } => echo.map(|v| v.to_string()),
+            other => panic!("unexpected status: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keeps_vars_and_echoes() {
+        let mut repl = Repl::new();
+        let output = repl.sys.capture_output();
+        assert_eq!(done(repl.feed_line("A=3")), None);
+        assert_eq!(done(repl.feed_line("A+1")), Some("4".to_string()));
+        // 表示の戻り値は表示しない
+        assert_eq!(done(repl.feed_line("Aを表示")), None);
+        assert_eq!(output.text(), "3\n");
+    }
+
+    #[test]
+    fn continues_unfinished_block() {
+        let mut repl = Repl::new();
+        assert!(matches!(repl.feed_line("●(Nを)倍増とは"), ReplStatus::NeedMore));
+        assert!(repl.is_pending());
+        assert!(matches!(repl.feed_line("  N*2で戻る"), ReplStatus::NeedMore));
+        assert_eq!(done(repl.feed_line("ここまで")), None);
+        assert_eq!(done(repl.feed_line("5を倍増")), Some("10".to_string()));
+        assert!(matches!(repl.feed_line("B=[1,"), ReplStatus::NeedMore));
+        done(repl.feed_line("2]"));
+    }
+
+    #[test]
+    fn recovers_after_errors() {
+        let mut repl = Repl::new();
+        done(repl.feed_line("A=1"));
+        assert!(matches!(repl.feed_line("A/0"), ReplStatus::Error { .. }));
+        assert!(matches!(repl.feed_line("Aを抜ける"), ReplStatus::Error { .. }));
+        // 空行で閉じていないブロックをそのまま実行する
+        assert!(matches!(repl.feed_line("2回"), ReplStatus::NeedMore));
+        assert!(matches!(repl.feed_line(""), ReplStatus::Error { .. }));
+        assert_eq!(done(repl.feed_line("A+1")), Some("2".to_string()));
+    }
+
+    #[test]
+    fn uses_options() {
+        // 最適化とレジスタVMの指定で実行しても結果は同じ
+        let mut repl = Repl::new();
+        repl.options.optimize = true;
+        repl.options.register_vm = true;
+        done(repl.feed_line("A=2*3"));
+        assert_eq!(repl.sys.codes.iter().filter(|code| code.kind == crate::bytecode::ByteCodeKind::Mul).count(), 0);
+        assert_eq!(done(repl.feed_line("A+1")), Some("7".to_string()));
+        assert!(matches!(repl.feed_line("A/0"), ReplStatus::Error { .. }));
+        assert_eq!(done(repl.feed_line("3回\nA=A+回数\nここまで\nA")), Some("12".to_string()));
+    }
+}
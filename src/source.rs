@@ -1,7 +1,7 @@
 /// source code character cursor module
 use crate::char_type;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct SourcePos {
     pub line: usize,
     pub column: usize,
@@ -67,13 +67,14 @@ impl Source {
         }
     }
     /// Get the next character and advance the cursor
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<char> {
         if self.index < self.source.len() {
             let ch = self.source[self.index];
             self.index += 1;
             if ch == '\n' {
                 self.pos.line += 1;
-                self.pos.column = 1;
+                self.pos.column = 0;
             } else {
                 self.pos.column += 1;
             }
@@ -122,6 +123,10 @@ impl Source {
     pub fn len(&self) -> usize {
         self.source.len()
     }
+    /// Check if the source is empty
+    pub fn is_empty(&self) -> bool {
+        self.source.is_empty()
+    }
     /// Get the token
     pub fn get_token(&mut self, end_of_token: char) -> String {
         let mut token = String::new();
@@ -155,7 +160,7 @@ impl Source {
         while let Some(ch) = self.next() {
             substring.push(ch);
             remain -= 1;
-            if remain <= 0 { break; }
+            if remain == 0 { break; }
         }
         substring
     }
@@ -227,10 +232,24 @@ mod tests {
         assert_eq!(cur.get_pos_tuple(), (0, 1));
 
         cur.next(); // '\n'
+        assert_eq!(cur.get_pos_tuple(), (1, 0));
+
+        cur.next();
         assert_eq!(cur.get_pos_tuple(), (1, 1));
+    }
 
+    #[test]
+    fn column_base_is_same_on_every_line() {
+        // 1行目も2行目以降も列は0から数える(エラー表示の^の位置がずれないように)
+        let mut cur = Source::new("ab\nab");
+        cur.next();
+        let first_line = cur.get_pos_tuple();
+        cur.get_n(2); // 'b', '\n'
         cur.next();
-        assert_eq!(cur.get_pos_tuple(), (1, 2));
+        let second_line = cur.get_pos_tuple();
+        assert_eq!(first_line, (0, 1));
+        assert_eq!(second_line, (1, 1));
+        assert_eq!(first_line.1, second_line.1);
     }
 
     #[test]
@@ -0,0 +1,44 @@
+//! System function module
+//! Built-in commands such as 「(値)を表示」, registered to the function table.
+
+use std::sync::Arc;
+
+use crate::bytecode::{NakoFuncTable, NakoSystem};
+use crate::value::Value;
+
+/// Register all system functions to the function table
+pub fn register_all(funcs: &mut NakoFuncTable) {
+    funcs.register_sys("表示", &[&["を", "と"]], Arc::new(sys_print));
+    funcs.register_sys("尋ねる", &[&["を", "と"]], Arc::new(sys_ask));
+    funcs.register_sys("標準入力取得", &[], Arc::new(sys_read_line));
+    funcs.register_sys("標準入力全取得", &[], Arc::new(sys_read_all));
+}
+
+/// 表示 --- print the value with a newline
+fn sys_print(sys: &mut NakoSystem, args: &[Value]) -> Result<Value, String> {
+    let value = args.first().cloned().unwrap_or(Value::None);
+    sys.println(&value.to_string());
+    Ok(Value::None)
+}
+
+/// 尋ねる --- print the message and read a line, numeric input becomes a number
+fn sys_ask(sys: &mut NakoSystem, args: &[Value]) -> Result<Value, String> {
+    let msg = args.first().cloned().unwrap_or(Value::None);
+    sys.print(&msg.to_string());
+    sys.output.flush();
+    let line = sys.input.read_line().unwrap_or_default();
+    match line.trim().parse::<f64>() {
+        Ok(n) => Ok(Value::from_number(n)),
+        Err(_) => Ok(Value::from_string(line)),
+    }
+}
+
+/// 標準入力取得 --- read a line from the input (empty at the end of the input)
+fn sys_read_line(sys: &mut NakoSystem, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::from_string(sys.input.read_line().unwrap_or_default()))
+}
+
+/// 標準入力全取得 --- read all of the rest of the input
+fn sys_read_all(sys: &mut NakoSystem, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::from_string(sys.input.read_all()))
+}
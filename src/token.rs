@@ -1,5 +1,5 @@
-/// Token module
-/// Defines the Token struct used by the lexer.
+//! Token module
+//! Defines the Token struct used by the lexer.
 
 use std::fmt;
 
@@ -14,21 +14,43 @@ pub enum TokenKind {
     Number,
     Str,
     Word,
-    Print,
     Plus,
     Minus,
     Mul,
     Div,
     ParenL,
     ParenR,
+    BracketL,
+    BracketR,
+    Comma,
     Eq,
+    EqEq,
+    NotEq,
+    Gt,
+    GtEq,
+    Lt,
+    LtEq,
     Wildcard,
+    If,
+    Else,
+    BlockEnd,
+    Times,
+    Repeat,
+    While,
+    Break,
+    Continue,
+    Foreach,
+    FuncDef,
+    Return,
 }
 impl TokenKind {
     pub fn is_operator(&self) -> bool {
         matches!(self,
             TokenKind::Plus | TokenKind::Minus |
-            TokenKind::Mul | TokenKind::Div
+            TokenKind::Mul | TokenKind::Div |
+            TokenKind::Eq | TokenKind::EqEq | TokenKind::NotEq |
+            TokenKind::Gt | TokenKind::GtEq |
+            TokenKind::Lt | TokenKind::LtEq
         )
     }
 }
@@ -40,6 +62,8 @@ pub struct Token {
     pub value: Option<String>,
     pub pos: SourcePos,
     pub josi: Option<String>,
+    /// the word as written with okurigana (None if it is the same as value)
+    pub raw: Option<String>,
 }
 impl Token {
     /// Create a new token with josi
@@ -49,11 +73,12 @@ impl Token {
             value: Some(value.to_string()),
             pos,
             josi: Some(josi.to_string()),
+            raw: None,
         }
     }
     /// Create a new token
     pub fn new(kind: TokenKind, value: Option<String>, pos: SourcePos) -> Self {
-        Self { kind, value, pos, josi: None }
+        Self { kind, value, pos, josi: None, raw: None }
     }
     /// Create a new Nop token
     pub fn new_nop() -> Self {
@@ -1,9 +1,12 @@
 //! value module
+use std::cmp::Ordering;
+use std::fmt;
 
 /// Defines the Value enum used in the VM stack and constants.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     None,
+    Bool(bool),
     Number(f64),
     String(String),
     Array(Vec<Value>),
@@ -14,6 +17,7 @@ impl Value {
         matches!(self, Value::None)
     }
     /// Create a Value from a &str
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         Value::String(s.to_string())
     }
@@ -21,37 +25,70 @@ impl Value {
     pub fn from_string(s: String) -> Self {
         Value::String(s)
     }
+    /// Create a Value from a bool
+    pub fn from_bool(b: bool) -> Self {
+        Value::Bool(b)
+    }
     /// Create a Value from a f64 number
     pub fn from_number(n: f64) -> Self {
         Value::Number(n)
     }
-    /// Convert the Value to a String representation
-    pub fn to_string(&self) -> String {
+    /// Convert the Value to a boolean for conditions
+    /// None, 0, empty string and empty array are false
+    pub fn to_bool(&self) -> bool {
         match self {
-            Value::None => "None".to_string(),
-            Value::Number(n) => n.to_string(),
-            Value::String(s) => s.clone(),
-            Value::Array(arr) => {
-                let mut s = "[".to_string();
-                let mut first = true;
-                for v in arr {
-                    if !first {
-                        s.push_str(", ");
-                    }
-                    s.push_str(&v.to_string());
-                    first = false;
-                }
-                s.push(']');
-                s
-            }
+            Value::None => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0,
+            Value::String(s) => !s.is_empty(),
+            Value::Array(arr) => !arr.is_empty(),
         }
     }
+    /// Compare two values for equality
+    /// Compare as numbers if both can be converted, otherwise as strings
+    pub fn is_equal(&self, other: &Value) -> bool {
+        if let (Some(l), Some(r)) = (self.to_number(), other.to_number()) {
+            return l == r;
+        }
+        self.to_string() == other.to_string()
+    }
+    /// Compare two values for ordering
+    /// Compare as numbers if both can be converted, otherwise as strings
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        if let (Some(l), Some(r)) = (self.to_number(), other.to_number()) {
+            return l.partial_cmp(&r);
+        }
+        Some(self.to_string().cmp(&other.to_string()))
+    }
     /// Convert the Value to a number (f64) if possible
     pub fn to_number(&self) -> Option<f64> {
         match self {
+            Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
             Value::Number(n) => Some(*n),
             Value::String(s) => s.parse::<f64>().ok(),
             _ => None,
         }
     }
-}
\ No newline at end of file
+}
+
+/// Convert the Value to a String representation
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::None => write!(f, "None"),
+            Value::Bool(b) => write!(f, "{}", if *b { "はい" } else { "いいえ" }),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Array(arr) => {
+                write!(f, "[")?;
+                for (i, v) in arr.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! verify module
+//! Checks the codes before running: operand ranges, jump targets and the stack depth on every path.
+
+use crate::bytecode::{ByteCode, ByteCodeKind, NakoSystem, Operand};
+use crate::error::{ErrorCode, NakoError};
+use crate::source::SourcePos;
+
+/// What the earlier runs have checked, so that running more code checks only the new codes
+#[derive(Clone, Debug, Default)]
+pub struct Checked {
+    /// number of checked codes
+    len: usize,
+    const_len: usize,
+    var_len: usize,
+    /// address and arity of each function when checked
+    funcs: Vec<(usize, usize)>,
+    /// stack depth before each code of the main program (the last item is the depth at the end)
+    depths: Vec<Option<usize>>,
+}
+impl Checked {
+    /// Are the checked codes still the same? (only appending keeps them)
+    fn is_valid_for(&self, sys: &NakoSystem) -> bool {
+        self.len <= sys.codes.len()
+            && self.const_len <= sys.const_list.len()
+            && self.var_len <= sys.var_table.vars.len()
+            && self.funcs.len() <= sys.func_table.funcs.len()
+            && self.funcs.iter().zip(&sys.func_table.funcs).all(|(&(addr, arity), func)| addr == func.addr && arity == func.arity())
+    }
+}
+
+/// Check all codes of the system, and return the first problem found
+pub fn verify(sys: &NakoSystem) -> Result<(), NakoError> {
+    check_from(sys, Checked::default()).map(|_| ())
+}
+
+/// Check the codes added since the last check (all codes when the earlier ones have changed),
+/// and return the index of the first code checked now
+pub fn verify_more(sys: &mut NakoSystem) -> Result<usize, NakoError> {
+    let checked = std::mem::take(&mut sys.checked);
+    let checked = if checked.is_valid_for(sys) { checked } else { Checked::default() };
+    let from = checked.len;
+    sys.checked = check_from(sys, checked)?;
+    Ok(from)
+}
+
+/// Check the codes and functions that are not in `checked` yet
+fn check_from(sys: &NakoSystem, checked: Checked) -> Result<Checked, NakoError> {
+    let from = checked.len;
+    for (pc, code) in sys.codes.iter().enumerate().skip(from) {
+        check_operands(sys, pc, code)?;
+    }
+    let new_funcs = || sys.func_table.funcs.iter().skip(checked.funcs.len()).filter(|func| func.sys_func.is_none());
+    for func in new_funcs() {
+        if func.addr >= sys.codes.len() {
+            return Err(error(sys, 0, ErrorCode::BadJumpTarget,
+                &format!("関数『{}』の開始位置{}が命令の範囲外です", func.display_name, func.addr)));
+        }
+        if let Some(&index) = func.local_vars.iter().find(|&&index| index >= sys.var_table.vars.len()) {
+            return Err(error(sys, func.addr, ErrorCode::BadOperandIndex,
+                &format!("関数『{}』のローカル変数の番号{}が不正です", func.display_name, index)));
+        }
+    }
+    // the main program starts at 0 and goes on to the new codes from where it ended,
+    // and each function body starts at its address
+    let mut depths = checked.depths;
+    depths.resize(sys.codes.len() + 1, None);
+    let depth = depths[from].take().unwrap_or(0);
+    check_stack(sys, from, depth, false, &mut depths)?;
+    for func in new_funcs() {
+        check_stack(sys, func.addr, 0, true, &mut vec![None; sys.codes.len() + 1])?;
+    }
+    Ok(Checked {
+        len: sys.codes.len(),
+        const_len: sys.const_list.len(),
+        var_len: sys.var_table.vars.len(),
+        funcs: sys.func_table.funcs.iter().map(|func| (func.addr, func.arity())).collect(),
+        depths,
+    })
+}
+
+/// Stack depth before each code of verified codes (None for unreachable codes).
+/// The last item is the depth at the end of the main program.
+pub(crate) fn stack_depths(sys: &NakoSystem) -> Result<Vec<Option<usize>>, NakoError> {
+    let mut depths = vec![None; sys.codes.len() + 1];
+    check_stack(sys, 0, 0, false, &mut depths)?;
+    for func in &sys.func_table.funcs {
+        if func.sys_func.is_none() {
+            check_stack(sys, func.addr, 0, true, &mut depths)?;
+        }
+    }
+    Ok(depths)
+}
+
+/// Check that the args point to existing constants, variables, functions and codes
+fn check_operands(sys: &NakoSystem, pc: usize, code: &ByteCode) -> Result<(), NakoError> {
+    let args = [code.arg1, code.arg2, code.arg3];
+    for (op, &arg) in code.kind.operands().iter().zip(&args) {
+        let (len, what) = match op {
+            Operand::Const => (sys.const_list.len(), "定数"),
+            Operand::Var => (sys.var_table.vars.len(), "変数"),
+            Operand::Func => (sys.func_table.len(), "関数"),
+            Operand::Addr => {
+                // jumping to the end finishes the program
+                if arg > sys.codes.len() {
+                    return Err(error(sys, pc, ErrorCode::BadJumpTarget,
+                        &format!("ジャンプ先{}が命令の範囲外です", arg)));
+                }
+                continue;
+            },
+            Operand::Count | Operand::Line => continue,
+        };
+        if arg >= len {
+            return Err(error(sys, pc, ErrorCode::BadOperandIndex, &format!("{}の番号{}が不正です", what, arg)));
+        }
+    }
+    if code.kind == ByteCodeKind::Call {
+        let func = &sys.func_table.funcs[code.arg1];
+        if func.arity() != code.arg2 {
+            return Err(error(sys, pc, ErrorCode::ArityMismatch,
+                &format!("関数『{}』の引数は{}個ですが{}個で呼ばれています", func.display_name, func.arity(), code.arg2)));
+        }
+    }
+    Ok(())
+}
+
+/// Follow every path from `start` (with `depth` values on the stack) and check that the stack depth
+/// never goes below zero and is the same whenever a code is reached
+fn check_stack(sys: &NakoSystem, start: usize, depth: usize, in_func: bool, depths: &mut [Option<usize>]) -> Result<(), NakoError> {
+    let len = sys.codes.len();
+    let mut work = vec![(start, depth)];
+    while let Some((pc, depth)) = work.pop() {
+        match depths[pc] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(error(sys, pc, ErrorCode::StackDepthMismatch,
+                    &format!("経路によってスタックの深さが違います({}と{})", known, depth)));
+            },
+            None => depths[pc] = Some(depth),
+        }
+        if pc == len {
+            if in_func {
+                return Err(error(sys, pc, ErrorCode::MisplacedReturn, "関数が『Return』せずに終わります"));
+            }
+            continue;
+        }
+        let code = &sys.codes[pc];
+        let (pops, pushes) = stack_effect(code);
+        if depth < pops {
+            return Err(error(sys, pc, ErrorCode::StackDepthUnderflow,
+                &format!("『{}』に必要な値がスタックにありません", code.kind.name())));
+        }
+        let next = depth - pops + pushes;
+        match code.kind {
+            ByteCodeKind::Return => {
+                if !in_func {
+                    return Err(error(sys, pc, ErrorCode::MisplacedReturn, "関数の外に『Return』があります"));
+                }
+            },
+            ByteCodeKind::Jump => work.push((code.arg1, next)),
+            ByteCodeKind::JumpIfFalse | ByteCodeKind::JumpIfTrue => {
+                work.push((code.arg1, next));
+                work.push((pc + 1, next));
+            },
+            // leave the loop without pushing anything
+            ByteCodeKind::CountUp => {
+                work.push((code.arg3, depth));
+                work.push((pc + 1, next));
+            },
+            ByteCodeKind::ForeachNext => {
+                work.push((code.arg3, depth));
+                work.push((pc + 1, next));
+            },
+            _ => work.push((pc + 1, next)),
+        }
+    }
+    Ok(())
+}
+
+/// Number of values popped and pushed by the code
+pub(crate) fn stack_effect(code: &ByteCode) -> (usize, usize) {
+    match code.kind {
+        ByteCodeKind::Nop | ByteCodeKind::EOS | ByteCodeKind::Jump | ByteCodeKind::CountUp => (0, 0),
+        ByteCodeKind::PushConst | ByteCodeKind::PushVariable => (0, 1),
+        ByteCodeKind::Add | ByteCodeKind::Sub | ByteCodeKind::Mul | ByteCodeKind::Div
+        | ByteCodeKind::Eq | ByteCodeKind::NotEq | ByteCodeKind::Gt | ByteCodeKind::GtEq
+        | ByteCodeKind::Lt | ByteCodeKind::LtEq => (2, 1),
+        ByteCodeKind::Let | ByteCodeKind::Pop | ByteCodeKind::Return
+        | ByteCodeKind::JumpIfFalse | ByteCodeKind::JumpIfTrue => (1, 0),
+        ByteCodeKind::ForStep | ByteCodeKind::ForCheck => (3, 1),
+        ByteCodeKind::ForeachNext => (0, 2),
+        ByteCodeKind::MakeArray => (code.arg1, 1),
+        ByteCodeKind::Call => (code.arg2, 1),
+    }
+}
+
+fn error(sys: &NakoSystem, pc: usize, code: ErrorCode, msg: &str) -> NakoError {
+    let pos = sys.spans.get(pc).copied().unwrap_or(SourcePos::zero());
+    NakoError::verify(code, pos, &format!("{}番目の命令: {}", pc + 1, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn verify_asm(text: &str) -> Result<(), ErrorCode> {
+        verify(&assemble(text).unwrap()).map_err(|err| err.code())
+    }
+
+    #[test]
+    fn accepts_compiled_code() {
+        let code = "●(Nを)倍増とは\nもしNが0ならば0で戻る\nN*2で戻る\nここまで\n3回\n回数を倍増して表示\nここまで\n[1,2]を反復\nもし対象が2ならば抜ける\nここまで\nA=0\nA<3の間\nA=A+1\nここまで";
+        let sys = crate::compile(code, &crate::NakoOptions::new()).unwrap();
+        assert_eq!(verify(&sys), Ok(()));
+    }
+
+    #[test]
+    fn checks_only_new_codes() {
+        let options = crate::NakoOptions::new();
+        let mut sys = crate::compile("●(Nを)倍増とは\nN*2で戻る\nここまで\nA=1", &options).unwrap();
+        assert_eq!(verify_more(&mut sys), Ok(0));
+        let start = crate::compile_into(&mut sys, "Aを倍増して表示", &options).unwrap();
+        assert_eq!(verify_more(&mut sys), Ok(start));
+        // 新しい命令の誤りも見つける
+        sys.codes.push(ByteCode::new(ByteCodeKind::PushConst, 99, 0, 0));
+        sys.spans.push(SourcePos::zero());
+        assert_eq!(verify_more(&mut sys).map_err(|err| err.code()), Err(ErrorCode::BadOperandIndex));
+        sys.codes.pop();
+        sys.spans.pop();
+        assert_eq!(verify_more(&mut sys), Ok(0));
+        // 関数を定義し直したときや命令を書き換えたときは全部を検査し直す
+        crate::compile_into(&mut sys, "●(Nを)倍増とは\nN*3で戻る\nここまで", &options).unwrap();
+        assert_eq!(verify_more(&mut sys), Ok(0));
+        sys.codes_changed();
+        assert_eq!(verify_more(&mut sys), Ok(0));
+    }
+
+    #[test]
+    fn rejects_bad_code() {
+        assert_eq!(verify_asm("PushConst #5"), Err(ErrorCode::BadOperandIndex));
+        assert_eq!(verify_asm("PushVariable #9"), Err(ErrorCode::BadOperandIndex));
+        assert_eq!(verify_asm("Jump #3"), Err(ErrorCode::BadJumpTarget));
+        assert_eq!(verify_asm("PushConst 1\nCall 表示 2"), Err(ErrorCode::ArityMismatch));
+        assert_eq!(verify_asm("PushConst 1\nAdd"), Err(ErrorCode::StackDepthUnderflow));
+        assert_eq!(verify_asm("PushConst 1\nReturn"), Err(ErrorCode::MisplacedReturn));
+        // ループを回るたびにスタックが増える
+        assert_eq!(verify_asm("top:\nPushConst 1\nJump top"), Err(ErrorCode::StackDepthMismatch));
+        // 分岐の片方だけが値を残す
+        assert_eq!(verify_asm("PushConst true\nJumpIfFalse end\nPushConst 1\nend:\nPushConst 2\nLet A"), Err(ErrorCode::StackDepthMismatch));
+    }
+}
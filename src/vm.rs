@@ -1,35 +1,212 @@
 //! Virtual Machine module
-use crate::bytecode::{ByteCode, ByteCodeKind, NakoSystem};
+use std::cmp::Ordering;
+
+use crate::bytecode::{ByteCode, ByteCodeKind, CallFrame, NakoSystem};
+use crate::compact::{self, CompactCode};
+use crate::error::{ErrorCode, NakoError, TraceFrame};
+use crate::source::SourcePos;
+use crate::value::Value;
+use crate::verify;
+
+/// Maximum depth of function calls
+const MAX_CALL_DEPTH: usize = 10000;
 
 /// Run the VM with the given VM system
-pub fn run(sys: &mut NakoSystem) -> bool {
-    let mut pc: usize = 0;
+pub fn run(sys: &mut NakoSystem) -> Result<(), NakoError> {
+    run_from(sys, 0)
+}
+
+/// Run the VM from the given code index, keeping variables set by earlier runs
+/// The codes are verified first, and bad codes are rejected without running anything.
+pub fn run_from(sys: &mut NakoSystem, start: usize) -> Result<(), NakoError> {
+    // only the codes added since the last run are checked and packed
+    let from = verify::verify_more(sys)?;
+    let mut packed = std::mem::take(&mut sys.packed);
+    let all_packed = compact::pack_more(&mut packed, &sys.codes, from);
+    // the debug output is printed by the ByteCode loop
+    let result = if all_packed && !sys.is_debug {
+        exec_compact(sys, &packed, start)
+    } else {
+        exec_codes(sys, start)
+    };
+    sys.packed = packed;
+    sys.output.flush();
+    result
+}
+
+/// Run the VM with the loop on ByteCode instead of the compact codes
+/// (slower, kept for the debug output and for comparison in benchmarks)
+pub fn run_unpacked(sys: &mut NakoSystem) -> Result<(), NakoError> {
+    verify::verify(sys)?;
+    let result = exec_codes(sys, 0);
+    sys.output.flush();
+    result
+}
+
+/// Execute the codes from the given index until the end or an error
+fn exec_codes(sys: &mut NakoSystem, start: usize) -> Result<(), NakoError> {
+    sys.pc = start;
+    sys.frames.clear();
     let code_len = sys.codes.len();
 
-    while pc < code_len {
+    while sys.pc < code_len {
         // Copy out the current instruction to avoid borrowing sys while executing
-        let code = sys.codes[pc];
-        let result = match code.kind {
-            ByteCodeKind::Nop => exec_nop(sys, &code),
-            ByteCodeKind::EOS => exec_eos(sys, &code),
-            ByteCodeKind::PushConst => exec_push_const(sys, &code),
-            ByteCodeKind::PushVariable => exec_push_variable(sys, &code),
-            ByteCodeKind::Print => exec_print(sys, &code),
-            ByteCodeKind::Add => exec_add(sys, &code),
-            ByteCodeKind::Sub => exec_sub(sys, &code),
-            ByteCodeKind::Mul => exec_mul(sys, &code),
-            ByteCodeKind::Div => exec_div(sys, &code),
-            ByteCodeKind::Let => exec_let(sys, &code),
-        };
-        
-        if !result {
-            return false;
+        let code = sys.codes[sys.pc];
+        // Advance first so that jump codes can overwrite the next pc
+        sys.pc += 1;
+        if !exec_code(sys, &code) {
+            return Err(last_error(sys));
         }
-        
+    }
+
+    Ok(())
+}
+
+/// Execute the compact codes from the given index until the end or an error.
+/// The common codes on numbers run here with a local pc, and the others
+/// (and every failing case, for the same error messages) go to `exec_code`.
+fn exec_compact(sys: &mut NakoSystem, codes: &[CompactCode], start: usize) -> Result<(), NakoError> {
+    sys.frames.clear();
+    let mut pc = start;
+    while let Some(&code) = codes.get(pc) {
         pc += 1;
+        let done = match code.kind {
+            ByteCodeKind::Nop => true,
+            ByteCodeKind::EOS => {
+                sys.src_lineno = code.arg1 as usize;
+                true
+            },
+            ByteCodeKind::PushConst => match sys.const_list.get(code.arg1 as usize) {
+                Some(value) => {
+                    sys.stack.push(value.clone());
+                    true
+                },
+                None => false,
+            },
+            ByteCodeKind::PushVariable => match sys.var_table.get_by_index(code.arg1 as usize) {
+                Some(value) => {
+                    sys.stack.push(value.clone());
+                    true
+                },
+                None => false,
+            },
+            ByteCodeKind::Let => match sys.stack.pop() {
+                Some(value) => {
+                    sys.var_table.set_by_index(code.arg1 as usize, value);
+                    true
+                },
+                None => false,
+            },
+            ByteCodeKind::Add | ByteCodeKind::Sub | ByteCodeKind::Mul | ByteCodeKind::Div
+            | ByteCodeKind::Eq | ByteCodeKind::NotEq | ByteCodeKind::Gt | ByteCodeKind::GtEq
+            | ByteCodeKind::Lt | ByteCodeKind::LtEq => {
+                // calculate in place on the top of the stack
+                let len = sys.stack.len();
+                if len >= 2
+                    && let (Value::Number(l), Value::Number(r)) = (&sys.stack[len - 2], &sys.stack[len - 1])
+                    && let Some(value) = number_op(code.kind, *l, *r) {
+                    sys.stack.pop();
+                    sys.stack[len - 2] = value;
+                    true
+                } else {
+                    false
+                }
+            },
+            ByteCodeKind::Jump => {
+                pc = code.arg1 as usize;
+                true
+            },
+            ByteCodeKind::JumpIfFalse | ByteCodeKind::JumpIfTrue => match sys.stack.pop() {
+                Some(value) => {
+                    if value.to_bool() == (code.kind == ByteCodeKind::JumpIfTrue) {
+                        pc = code.arg1 as usize;
+                    }
+                    true
+                },
+                None => false,
+            },
+            ByteCodeKind::Pop => sys.stack.pop().is_some(),
+            ByteCodeKind::CountUp => match (sys.var_table.get_by_index(code.arg1 as usize), sys.var_table.get_by_index(code.arg2 as usize)) {
+                (Some(Value::Number(counter)), Some(Value::Number(limit))) => {
+                    let (counter, limit) = (counter + 1.0, *limit);
+                    sys.var_table.set_by_index(code.arg1 as usize, Value::Number(counter));
+                    if counter > limit {
+                        pc = code.arg3 as usize;
+                    }
+                    true
+                },
+                _ => false,
+            },
+            _ => false,
+        };
+        if done {
+            continue;
+        }
+        sys.pc = pc;
+        if !exec_code(sys, &code.unpack()) {
+            return Err(last_error(sys));
+        }
+        pc = sys.pc;
     }
+    sys.pc = pc;
+    Ok(())
+}
 
-    true
+/// Calculate the operator on two numbers, or None to leave it to `binary_op`
+pub(crate) fn number_op(kind: ByteCodeKind, l: f64, r: f64) -> Option<Value> {
+    let value = match kind {
+        ByteCodeKind::Add => Value::Number(l + r),
+        ByteCodeKind::Sub => Value::Number(l - r),
+        ByteCodeKind::Mul => Value::Number(l * r),
+        ByteCodeKind::Div if r != 0.0 => Value::Number(l / r),
+        ByteCodeKind::Eq => Value::Bool(l == r),
+        ByteCodeKind::NotEq => Value::Bool(l != r),
+        ByteCodeKind::Gt => Value::Bool(l > r),
+        ByteCodeKind::GtEq => Value::Bool(l >= r),
+        ByteCodeKind::Lt => Value::Bool(l < r),
+        ByteCodeKind::LtEq => Value::Bool(l <= r),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Execute one code (sys.pc has already been advanced)
+pub(crate) fn exec_code(sys: &mut NakoSystem, code: &ByteCode) -> bool {
+    match code.kind {
+        ByteCodeKind::Nop => exec_nop(sys, code),
+        ByteCodeKind::EOS => exec_eos(sys, code),
+        ByteCodeKind::PushConst => exec_push_const(sys, code),
+        ByteCodeKind::PushVariable => exec_push_variable(sys, code),
+        ByteCodeKind::Add => exec_binary(sys, code, "足し算"),
+        ByteCodeKind::Sub => exec_binary(sys, code, "引き算"),
+        ByteCodeKind::Mul => exec_binary(sys, code, "掛け算"),
+        ByteCodeKind::Div => exec_binary(sys, code, "割り算"),
+        ByteCodeKind::Let => exec_let(sys, code),
+        ByteCodeKind::Eq => exec_binary(sys, code, "比較(EQ)"),
+        ByteCodeKind::NotEq => exec_binary(sys, code, "比較(NOT_EQ)"),
+        ByteCodeKind::Gt => exec_binary(sys, code, "比較(GT)"),
+        ByteCodeKind::GtEq => exec_binary(sys, code, "比較(GT_EQ)"),
+        ByteCodeKind::Lt => exec_binary(sys, code, "比較(LT)"),
+        ByteCodeKind::LtEq => exec_binary(sys, code, "比較(LT_EQ)"),
+        ByteCodeKind::Jump => exec_jump(sys, code),
+        ByteCodeKind::JumpIfFalse => exec_jump_if(sys, code, false),
+        ByteCodeKind::JumpIfTrue => exec_jump_if(sys, code, true),
+        ByteCodeKind::Pop => exec_pop(sys, code),
+        ByteCodeKind::CountUp => exec_count_up(sys, code),
+        ByteCodeKind::ForStep => exec_for_step(sys, code),
+        ByteCodeKind::ForCheck => exec_for_check(sys, code),
+        ByteCodeKind::MakeArray => exec_make_array(sys, code),
+        ByteCodeKind::ForeachNext => exec_foreach_next(sys, code),
+        ByteCodeKind::Call => exec_call(sys, code),
+        ByteCodeKind::Return => exec_return(sys, code),
+    }
+}
+
+/// Take the error recorded by the failed code, so that it does not stay in the system
+pub(crate) fn last_error(sys: &mut NakoSystem) -> NakoError {
+    std::mem::take(&mut sys.errors).pop().unwrap_or_else(|| {
+        NakoError::runtime(ErrorCode::InvalidOperand, SourcePos::new(sys.src_lineno, 0), "実行に失敗しました")
+    })
 }
 
 fn exec_nop(_sys: &mut NakoSystem, _code: &ByteCode) -> bool {
@@ -46,11 +223,11 @@ fn exec_let(sys: &mut NakoSystem, code: &ByteCode) -> bool {
     let var_index = code.arg1;
     if let Some(value) = sys.stack.pop() {
         if sys.is_debug {
-            println!("LET: var_index={}, value={:?}", var_index, value);
+            eprintln!("LET: var_index={}, value={:?}", var_index, value);
         }
         sys.var_table.set_by_index(var_index, value);
     } else {
-        sys.error("Stack underflow on LET operation");
+        sys.runtime_error(ErrorCode::StackUnderflow, "代入の値が足りません(スタック不足)");
         return false;
     }
     true
@@ -60,99 +237,319 @@ fn exec_push_const(sys: &mut NakoSystem, code: &ByteCode) -> bool {
     if code.arg1 < sys.const_list.len() {
         let value = sys.const_list[code.arg1].clone();
         if sys.is_debug {
-            println!("PUSH_CONST: {:?}", value);
+            eprintln!("PUSH_CONST: {:?}", value);
         }
         sys.stack.push(value);
         true
     } else {
-        sys.error(&format!("Invalid constant index: {}", code.arg1));
+        sys.runtime_error(ErrorCode::InvalidOperand, &format!("定数の番号{}が不正です", code.arg1));
         false
     }
 }
 
 fn exec_push_variable(sys: &mut NakoSystem, code: &ByteCode) -> bool {
     let var_index = code.arg1;
-    if var_index < sys.var_table.len() {
-        if let Some(value) = sys.var_table.get_by_index(var_index) {
-            sys.stack.push(value.clone());
-            return true;
-        }
+    if var_index < sys.var_table.len()
+        && let Some(value) = sys.var_table.get_by_index(var_index) {
+        sys.stack.push(value.clone());
+        return true;
     }
-    sys.error(&format!("Invalid constant index: {}", code.arg1));
+    sys.runtime_error(ErrorCode::InvalidOperand, &format!("変数の番号{}が不正です", code.arg1));
     false
 }
 
-fn exec_print(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
+/// Pop two values and push the result of the operator
+fn exec_binary(sys: &mut NakoSystem, code: &ByteCode, name: &str) -> bool {
+    let (Some(right), Some(left)) = (sys.stack.pop(), sys.stack.pop()) else {
+        sys.runtime_error(ErrorCode::StackUnderflow, &format!("{}の値が足りません(スタック不足)", name));
+        return false;
+    };
+    match binary_op(code.kind, &left, &right) {
+        Ok(value) => {
+            sys.stack.push(value);
+            true
+        },
+        Err((err_code, msg)) => {
+            sys.runtime_error(err_code, msg);
+            false
+        }
+    }
+}
+
+/// Calculate the binary operator (also used by the optimizer to fold constants)
+pub fn binary_op(kind: ByteCodeKind, left: &Value, right: &Value) -> Result<Value, (ErrorCode, &'static str)> {
+    let result = match kind {
+        ByteCodeKind::Eq => left.is_equal(right),
+        ByteCodeKind::NotEq => !left.is_equal(right),
+        ByteCodeKind::Gt => left.compare(right) == Some(Ordering::Greater),
+        ByteCodeKind::GtEq => matches!(left.compare(right), Some(Ordering::Greater | Ordering::Equal)),
+        ByteCodeKind::Lt => left.compare(right) == Some(Ordering::Less),
+        ByteCodeKind::LtEq => matches!(left.compare(right), Some(Ordering::Less | Ordering::Equal)),
+        _ => return calc_number(kind, left, right),
+    };
+    Ok(Value::from_bool(result))
+}
+
+/// Calculate the arithmetic operator
+fn calc_number(kind: ByteCodeKind, left: &Value, right: &Value) -> Result<Value, (ErrorCode, &'static str)> {
+    let (Some(l), Some(r)) = (left.to_number(), right.to_number()) else {
+        let msg = match kind {
+            ByteCodeKind::Add => "足し算には数値が必要です",
+            ByteCodeKind::Sub => "引き算には数値が必要です",
+            ByteCodeKind::Mul => "掛け算には数値が必要です",
+            _ => "割り算には数値が必要です",
+        };
+        return Err((ErrorCode::TypeMismatch, msg));
+    };
+    let value = match kind {
+        ByteCodeKind::Add => l + r,
+        ByteCodeKind::Sub => l - r,
+        ByteCodeKind::Mul => l * r,
+        ByteCodeKind::Div if r == 0.0 => return Err((ErrorCode::DivisionByZero, "0で割ることはできません")),
+        ByteCodeKind::Div => l / r,
+        _ => return Err((ErrorCode::InvalidOperand, "二項演算子ではありません")),
+    };
+    Ok(Value::from_number(value))
+}
+
+fn exec_jump(sys: &mut NakoSystem, code: &ByteCode) -> bool {
+    sys.pc = code.arg1;
+    true
+}
+
+/// Pop the condition and jump to arg1 if it equals `when`
+fn exec_jump_if(sys: &mut NakoSystem, code: &ByteCode, when: bool) -> bool {
     if let Some(value) = sys.stack.pop() {
-        println!("[PRINT]{:?}", value);
-        sys.println(&value.to_string());
+        if value.to_bool() == when {
+            sys.pc = code.arg1;
+        }
         true
     } else {
-        sys.error("Stack underflow on PRINT operation");
+        sys.runtime_error(ErrorCode::StackUnderflow, "条件分岐の値が足りません(スタック不足)");
         false
     }
 }
 
-fn exec_add(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
-    if let (Some(right), Some(left)) = (sys.stack.pop(), sys.stack.pop()) {
-        if let (Some(l), Some(r)) = (left.to_number(), right.to_number()) {
-            sys.stack.push(crate::value::Value::from_number(l + r));
+/// Discard the value on the top of the stack
+fn exec_pop(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
+    if sys.stack.pop().is_none() {
+        sys.runtime_error(ErrorCode::StackUnderflow, "捨てる値がありません(スタック不足)");
+        return false;
+    }
+    true
+}
+
+/// counter(arg1) += 1, and jump to arg3 if counter > limit(arg2)
+fn exec_count_up(sys: &mut NakoSystem, code: &ByteCode) -> bool {
+    let counter = sys.var_table.get_by_index(code.arg1).and_then(|v| v.to_number());
+    let limit = sys.var_table.get_by_index(code.arg2).and_then(|v| v.to_number());
+    let (Some(counter), Some(limit)) = (counter, limit) else {
+        sys.runtime_error(ErrorCode::TypeMismatch, "『回』には数値が必要です");
+        return false;
+    };
+    let counter = counter + 1.0;
+    sys.var_table.set_by_index(code.arg1, Value::from_number(counter));
+    if counter > limit {
+        sys.pc = code.arg3;
+    }
+    true
+}
+
+/// Pop step, to and from, and push the step signed by the direction of the range
+fn exec_for_step(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
+    if let (Some(step), Some(to), Some(from)) = (sys.stack.pop(), sys.stack.pop(), sys.stack.pop()) {
+        if let (Some(s), Some(f), Some(t)) = (step.to_number(), from.to_number(), to.to_number()) {
+            let step = if f <= t { s.abs() } else { -s.abs() };
+            sys.stack.push(Value::from_number(step));
             true
         } else {
-            sys.error("ADD operation requires numeric values");
+            sys.runtime_error(ErrorCode::TypeMismatch, "繰り返しには数値が必要です");
             false
         }
     } else {
-        sys.error("Stack underflow on ADD operation");
+        sys.runtime_error(ErrorCode::StackUnderflow, "繰り返しの値が足りません(スタック不足)");
         false
     }
 }
 
-fn exec_sub(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
-    if let (Some(right), Some(left)) = (sys.stack.pop(), sys.stack.pop()) {
-        if let (Some(l), Some(r)) = (left.to_number(), right.to_number()) {
-            sys.stack.push(crate::value::Value::from_number(l - r));
+/// Pop step, to and the loop value, and push whether the loop continues
+fn exec_for_check(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
+    if let (Some(step), Some(to), Some(value)) = (sys.stack.pop(), sys.stack.pop(), sys.stack.pop()) {
+        if let (Some(s), Some(t), Some(v)) = (step.to_number(), to.to_number(), value.to_number()) {
+            if s == 0.0 {
+                sys.runtime_error(ErrorCode::ZeroStep, "『繰り返す』の増分に0は指定できません");
+                return false;
+            }
+            let result = if s > 0.0 { v <= t } else { v >= t };
+            sys.stack.push(Value::from_bool(result));
             true
         } else {
-            sys.error("SUB operation requires numeric values");
+            sys.runtime_error(ErrorCode::TypeMismatch, "繰り返しには数値が必要です");
             false
         }
     } else {
-        sys.error("Stack underflow on SUB operation");
+        sys.runtime_error(ErrorCode::StackUnderflow, "繰り返しの値が足りません(スタック不足)");
         false
     }
 }
 
-fn exec_mul(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
-    if let (Some(right), Some(left)) = (sys.stack.pop(), sys.stack.pop()) {
-        if let (Some(l), Some(r)) = (left.to_number(), right.to_number()) {
-            sys.stack.push(crate::value::Value::from_number(l * r));
-            true
-        } else {
-            sys.error("MUL operation requires numeric values");
-            false
+/// Pop arg1 values and push them as an array
+fn exec_make_array(sys: &mut NakoSystem, code: &ByteCode) -> bool {
+    if sys.stack.len() < code.arg1 {
+        sys.runtime_error(ErrorCode::StackUnderflow, "配列の作成の値が足りません(スタック不足)");
+        return false;
+    }
+    let items = sys.stack.split_off(sys.stack.len() - code.arg1);
+    sys.stack.push(Value::Array(items));
+    true
+}
+
+/// Push the element of target(arg1) at index(arg2) and its key, then index += 1
+/// Jump to arg3 when all elements have been visited
+fn exec_foreach_next(sys: &mut NakoSystem, code: &ByteCode) -> bool {
+    let index = sys.var_table.get_by_index(code.arg2).and_then(|v| v.to_number()).unwrap_or(0.0) as usize;
+    let item = match sys.var_table.get_by_index(code.arg1) {
+        Some(Value::Array(arr)) => arr.get(index).cloned(),
+        Some(Value::None) => None,
+        _ => {
+            sys.runtime_error(ErrorCode::NotArray, "『反復』には配列が必要です");
+            return false;
         }
-    } else {
-        sys.error("Stack underflow on MUL operation");
-        false
+    };
+    match item {
+        Some(item) => {
+            sys.stack.push(item);
+            sys.stack.push(Value::from_number(index as f64));
+            sys.var_table.set_by_index(code.arg2, Value::from_number((index + 1) as f64));
+        },
+        None => sys.pc = code.arg3,
     }
+    true
 }
 
-fn exec_div(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
-    if let (Some(right), Some(left)) = (sys.stack.pop(), sys.stack.pop()) {
-        if let (Some(l), Some(r)) = (left.to_number(), right.to_number()) {
-            if r == 0.0 {
-                sys.error("Division by zero");
+/// Call the function(arg1) with arg2 arguments on the stack
+fn exec_call(sys: &mut NakoSystem, code: &ByteCode) -> bool {
+    let Some(func) = sys.func_table.get_by_index(code.arg1) else {
+        sys.runtime_error(ErrorCode::InvalidOperand, &format!("関数の番号{}が不正です", code.arg1));
+        return false;
+    };
+    let (addr, local_vars, sys_func) = (func.addr, func.local_vars.clone(), func.sys_func.clone());
+    let func_name = func.display_name.clone();
+    if sys.stack.len() < code.arg2 {
+        sys.runtime_error(ErrorCode::StackUnderflow, "関数呼び出しの値が足りません(スタック不足)");
+        return false;
+    }
+    // system function: call the Rust function directly
+    if let Some(sys_func) = sys_func {
+        let args = sys.stack.split_off(sys.stack.len() - code.arg2);
+        match sys_func(sys, &args) {
+            Ok(value) => sys.stack.push(value),
+            Err(msg) => {
+                // the failed system function is the innermost frame
+                let pos = sys.current_pos();
+                let mut trace = sys.stack_trace();
+                trace.insert(0, TraceFrame { func_name, call_pos: pos, args });
+                sys.errors.push(NakoError::runtime(ErrorCode::SysFuncFailed, pos, &msg).with_trace(trace));
                 return false;
             }
-            sys.stack.push(crate::value::Value::from_number(l / r));
-            true
-        } else {
-            sys.error("DIV operation requires numeric values");
-            false
         }
-    } else {
-        sys.error("Stack underflow on DIV operation");
-        false
+        return true;
+    }
+    if sys.frames.len() >= MAX_CALL_DEPTH {
+        sys.runtime_error(ErrorCode::CallDepthExceeded, "関数の呼び出しが深すぎます");
+        return false;
+    }
+    let call_args = sys.stack.split_off(sys.stack.len() - code.arg2);
+    let mut args = call_args.clone().into_iter();
+    // save the local variables of the caller, and set the arguments
+    let mut saved_vars = Vec::with_capacity(local_vars.len());
+    for &var_index in local_vars.iter() {
+        saved_vars.push(sys.var_table.get_by_index(var_index).cloned().unwrap_or(Value::None));
+        sys.var_table.set_by_index(var_index, args.next().unwrap_or(Value::None));
+    }
+    sys.frames.push(CallFrame {
+        func_index: code.arg1,
+        return_pc: sys.pc,
+        stack_base: sys.stack.len(),
+        saved_vars,
+        call_pos: sys.current_pos(),
+        args: call_args,
+    });
+    sys.pc = addr;
+    true
+}
+
+/// Return from the function with the value on the stack
+fn exec_return(sys: &mut NakoSystem, _code: &ByteCode) -> bool {
+    let value = sys.stack.pop().unwrap_or(Value::None);
+    let Some(frame) = sys.frames.pop() else {
+        sys.runtime_error(ErrorCode::InvalidReturn, "関数の外で『戻る』が実行されました");
+        return false;
+    };
+    // restore the local variables of the caller
+    if let Some(func) = sys.func_table.get_by_index(frame.func_index) {
+        let local_vars = func.local_vars.clone();
+        for (var_index, saved) in local_vars.into_iter().zip(frame.saved_vars) {
+            sys.var_table.set_by_index(var_index, saved);
+        }
     }
-}
\ No newline at end of file
+    sys.stack.truncate(frame.stack_base);
+    sys.pc = frame.return_pc;
+    sys.stack.push(value);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_codes(codes: Vec<ByteCode>, consts: Vec<Value>) -> NakoSystem {
+        let mut sys = NakoSystem::new();
+        sys.var_table.get_name_index_create("A");
+        sys.spans = vec![SourcePos::zero(); codes.len()];
+        sys.codes = codes;
+        sys.const_list = consts;
+        run(&mut sys).unwrap();
+        sys
+    }
+
+    #[test]
+    fn jump_if_true_and_pop() {
+        // 0: push true, 1: jump to 4 if true, 2: push 1, 3: let A, 4: push 2, 5: pop
+        let codes = vec![
+            ByteCode::new(ByteCodeKind::PushConst, 0, 0, 0),
+            ByteCode::new(ByteCodeKind::JumpIfTrue, 4, 0, 0),
+            ByteCode::new(ByteCodeKind::PushConst, 1, 0, 0),
+            ByteCode::new(ByteCodeKind::Let, 0, 0, 0),
+            ByteCode::new(ByteCodeKind::PushConst, 2, 0, 0),
+            ByteCode::new_code(ByteCodeKind::Pop),
+        ];
+        let consts = vec![Value::from_bool(true), Value::from_number(1.0), Value::from_number(2.0)];
+        let sys = run_codes(codes, consts);
+        assert!(sys.stack.is_empty());
+        assert_eq!(sys.var_table.get_by_index(0), Some(&Value::None));
+    }
+
+    #[test]
+    fn pop_empty_stack_is_error() {
+        let mut sys = NakoSystem::new();
+        sys.codes = vec![ByteCode::new_code(ByteCodeKind::Pop)];
+        sys.spans = vec![SourcePos::zero()];
+        // rejected by the verifier before running
+        let err = run(&mut sys).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StackDepthUnderflow);
+    }
+
+    #[test]
+    fn compact_loop_matches_bytecode_loop() {
+        // 数値の速い経路と文字列の遅い経路、エラーの位置も同じになる
+        let code = "A=0\n3回\nA=A+回数\nここまで\nB=「x」+1\n";
+        let mut compact = crate::compile(code, &crate::NakoOptions::new()).unwrap();
+        let mut unpacked = compact.clone();
+        let err1 = run(&mut compact).unwrap_err();
+        let err2 = run_unpacked(&mut unpacked).unwrap_err();
+        assert_eq!(err1.to_string(), err2.to_string());
+        assert_eq!(compact.var_table.get_by_index(0), Some(&Value::from_number(6.0)));
+        assert_eq!(compact.var_table.get_by_index(0), unpacked.var_table.get_by_index(0));
+    }
+}
@@ -28,7 +28,7 @@ fn test_division() {
 #[test]
 fn test_division_by_zero() {
     let output = run_test("10 / 0を表示");
-    assert!(output.contains("Division by zero"), "Error message should mention division by zero");
+    assert!(output.contains("0で割ることはできません"), "Error message should mention division by zero");
 }
 
 #[test]
@@ -61,3 +61,573 @@ fn test_let() {
     let output = run_test("A=30; Aを表示");
     assert_eq!(output.trim(), "30", "A=30; Aを表示。");
 }
+
+#[test]
+fn test_if_single_line() {
+    let output = run_test("A=5; もしA=5ならば「OK」を表示");
+    assert_eq!(output, "OK", "single-line if should run the statement");
+    let output = run_test("A=3; もしA=5ならば「OK」を表示");
+    assert_eq!(output, "", "single-line if should skip the statement");
+}
+
+#[test]
+fn test_if_single_line_else() {
+    let output = run_test("A=3\nもしAが5ならば「OK」を表示\n違えば「NG」を表示\n「END」を表示");
+    assert_eq!(output, "NG\nEND", "else on the next line should run");
+}
+
+#[test]
+fn test_if_block() {
+    let code = "A=5\nもしAが5ならば\n  「はい」を表示\n  「A=5」を表示\n違えば\n  「いいえ」を表示\nここまで\n「END」を表示";
+    assert_eq!(run_test(code), "はい\nA=5\nEND");
+    let code = "A=1\nもしAが5ならば\n  「はい」を表示\n違えば\n  「いいえ」を表示\nここまで";
+    assert_eq!(run_test(code), "いいえ");
+}
+
+#[test]
+fn test_if_else_if() {
+    let code = "A=2\nもしAが1ならば\n「一」を表示\n違えばもしAが2ならば\n「二」を表示\n違えば\n「他」を表示\nここまで";
+    assert_eq!(run_test(code), "二");
+}
+
+#[test]
+fn test_if_nested() {
+    let code = "A=1; B=2\nもしAが1ならば\n  もしB=2ならば\n    「両方」を表示\n  ここまで\nここまで";
+    assert_eq!(run_test(code), "両方");
+}
+
+#[test]
+fn test_operator_precedence() {
+    assert_eq!(run_test("1-2*3+4を表示"), "-1");
+    assert_eq!(run_test("2*(1+2)+3を表示"), "9");
+    assert_eq!(run_test("A=(1+2)*3; Aを表示"), "9");
+}
+
+#[test]
+fn test_compare_operators() {
+    assert_eq!(run_test("3>2を表示"), "はい");
+    assert_eq!(run_test("3<2を表示"), "いいえ");
+    assert_eq!(run_test("3>=3を表示"), "はい");
+    assert_eq!(run_test("3<=2を表示"), "いいえ");
+    assert_eq!(run_test("3==3を表示"), "はい");
+    assert_eq!(run_test("3!=3を表示"), "いいえ");
+    assert_eq!(run_test("3<>2を表示"), "はい");
+    assert_eq!(run_test("1+2＞2を表示"), "はい");
+    assert_eq!(run_test("2≧3を表示"), "いいえ");
+    assert_eq!(run_test("2≦3を表示"), "はい");
+    assert_eq!(run_test("2≠3を表示"), "はい");
+    assert_eq!(run_test("「abc」==「abc」を表示"), "はい");
+}
+
+#[test]
+fn test_if_compare() {
+    assert_eq!(run_test("A=10\nもしA>5ならば「大」を表示\n違えば「小」を表示"), "大");
+    assert_eq!(run_test("A=10\nもしA≦5ならば「小」を表示\n違えば「大」を表示"), "大");
+}
+
+#[test]
+fn test_if_compare_word() {
+    assert_eq!(run_test("A=5\nもしAが5以上ならば「OK」を表示"), "OK");
+    assert_eq!(run_test("A=5\nもしAが5以下ならば「OK」を表示"), "OK");
+    assert_eq!(run_test("A=5\nもしAが5未満ならば「OK」を表示\n違えば「NG」を表示"), "NG");
+    assert_eq!(run_test("A=5\nもしAが4超ならば「OK」を表示"), "OK");
+}
+
+#[test]
+fn test_times_block() {
+    assert_eq!(run_test("3回\n回数を表示\nここまで"), "1\n2\n3");
+    assert_eq!(run_test("N=2\n(N+1)回\n「あ」を表示\nここまで\n「END」を表示"), "あ\nあ\nあ\nEND");
+    assert_eq!(run_test("0回\n「あ」を表示\nここまで"), "");
+}
+
+#[test]
+fn test_times_single_line() {
+    assert_eq!(run_test("2回「あ」を表示"), "あ\nあ");
+}
+
+#[test]
+fn test_times_nested() {
+    let code = "S=0\n3回\n  2回\n    S=S+1\n  ここまで\nここまで\nSを表示";
+    assert_eq!(run_test(code), "6");
+}
+
+#[test]
+fn test_times_nested_kaisu() {
+    // 内側の繰り返しが終わると外側の回数に戻る
+    let code = "3回\n  2回\n    X=1\n  ここまで\n  回数を表示\nここまで";
+    assert_eq!(run_test(code), "1\n2\n3");
+    let code = "2回\n  3回\n    もし回数が2ならば抜ける\n  ここまで\n  回数を表示\nここまで";
+    assert_eq!(run_test(code), "1\n2");
+}
+
+#[test]
+fn test_for_range() {
+    assert_eq!(run_test("Iを1から3まで繰り返す\nIを表示\nここまで"), "1\n2\n3");
+    assert_eq!(run_test("S=0\nIを1から10まで繰り返す\nS=S+I\nここまで\nSを表示"), "55");
+}
+
+#[test]
+fn test_for_step() {
+    assert_eq!(run_test("Iを1から10まで4ずつ繰り返す\nIを表示\nここまで"), "1\n5\n9");
+    assert_eq!(run_test("Iを10から1まで3ずつ繰り返す\nIを表示\nここまで"), "10\n7\n4\n1");
+}
+
+#[test]
+fn test_for_descending() {
+    assert_eq!(run_test("Iを3から1まで繰り返す\nIを表示\nここまで"), "3\n2\n1");
+}
+
+#[test]
+fn test_for_single_line_and_default_var() {
+    assert_eq!(run_test("1から2まで繰り返す「あ」を表示"), "あ\nあ");
+    assert_eq!(run_test("1から2まで繰り返す\n対象を表示\nここまで"), "1\n2");
+}
+
+#[test]
+fn test_while() {
+    assert_eq!(run_test("A=1\nA<4の間\nAを表示\nA=A+1\nここまで"), "1\n2\n3");
+    assert_eq!(run_test("A=1\nAが1の間\nA=A+1\nここまで\nAを表示"), "2");
+    assert_eq!(run_test("A=1\nAが3未満の間\nA=A+1\nここまで\nAを表示"), "3");
+}
+
+#[test]
+fn test_while_break_continue() {
+    let code = "A=0\nA<10の間\n  A=A+1\n  もしAが2ならば続ける\n  もしAが4ならば抜ける\n  Aを表示\nここまで";
+    assert_eq!(run_test(code), "1\n3");
+}
+
+#[test]
+fn test_break_continue_in_other_loops() {
+    assert_eq!(run_test("5回\nもし回数が2ならば続ける\nもし回数が4ならば抜ける\n回数を表示\nここまで"), "1\n3");
+    assert_eq!(run_test("Iを1から5まで繰り返す\nもしIが2ならば続ける\nもしIが4ならば抜ける\nIを表示\nここまで"), "1\n3");
+}
+
+#[test]
+fn test_break_nested_loops() {
+    let code = "2回\n  J=回数\n  3回\n    もし回数が2ならば抜ける\n    Jを表示\n  ここまで\nここまで";
+    assert_eq!(run_test(code), "1\n2");
+}
+
+#[test]
+fn test_array_literal() {
+    assert_eq!(run_test("[1,2,3]を表示"), "[1, 2, 3]");
+    assert_eq!(run_test("A=[「a」, 1+2, [4]]; Aを表示"), "[a, 3, [4]]");
+    assert_eq!(run_test("[]を表示"), "[]");
+}
+
+#[test]
+fn test_foreach() {
+    assert_eq!(run_test("[「a」,「b」,「c」]を反復\n対象キーを表示\n対象を表示\nここまで"), "0\na\n1\nb\n2\nc");
+    assert_eq!(run_test("A=[1,2,3]\nS=0\nAを反復\nS=S+対象\nここまで\nSを表示"), "6");
+    assert_eq!(run_test("[1,2,3,4]を反復\nもし対象が2ならば続ける\nもし対象が4ならば抜ける\n対象を表示\nここまで"), "1\n3");
+}
+
+#[test]
+fn test_foreach_nested() {
+    // 内側の反復が終わると外側の対象と対象キーに戻る
+    let code = "A=[1,2]\nAを反復\n  [7,8]を反復\n    X=対象\n  ここまで\n  対象キーを表示\n  対象を表示\nここまで";
+    assert_eq!(run_test(code), "0\n1\n1\n2");
+}
+
+#[test]
+fn test_func_call_by_josi() {
+    let code = "●(AとBを)足し算とは\n  A+Bで戻る\nここまで\n5を3と足し算を表示\n3と5を足し算を表示";
+    assert_eq!(run_test(code), "8\n8");
+    let code = "●(AからBを)引き算とは\n  A-Bで戻る\nここまで\n3を10から引き算を表示\n10から3を引き算を表示";
+    assert_eq!(run_test(code), "7\n7");
+}
+
+#[test]
+fn test_func_call_before_definition() {
+    let code = "2を倍増して表示\n●(Xを)倍増とは\n  X*2で戻る\nここまで";
+    assert_eq!(run_test(code), "4");
+}
+
+#[test]
+fn test_func_sore_and_locals() {
+    let code = "A=100\n●(Xを)処理とは\n  A=X+1\n  それ=A*2\nここまで\n5を処理\nそれを表示\nAを表示";
+    assert_eq!(run_test(code), "12\n100");
+}
+
+#[test]
+fn test_func_recursion() {
+    let code = "●(Nの)階乗とは\n  もしNが1以下ならば1で戻る\n  (N-1)の階乗*Nで戻る\nここまで\n5の階乗を表示";
+    assert_eq!(run_test(code), "120");
+    let code = "●(Nの)合計とは\n  S=0\n  Iを1からNまで繰り返す\n    S=S+I\n  ここまで\n  Sで戻る\nここまで\n10の合計を表示";
+    assert_eq!(run_test(code), "55");
+}
+
+#[test]
+fn test_func_in_expression() {
+    let code = "●(AとBを)足し算とは\n  A+Bで戻る\nここまで\nX=(1と2を足し算)*10\nXを表示";
+    assert_eq!(run_test(code), "30");
+}
+
+#[test]
+fn test_print_as_sys_func() {
+    assert_eq!(run_test("「abc」と表示"), "abc");
+    // 引数を省略すると「それ」を表示する
+    assert_eq!(run_test("それ=「省略」\n表示"), "省略");
+    // 同名の関数を定義すると上書きできる
+    let code = "●(Xを)表示とは\n  それ=X\nここまで\n5を表示";
+    assert_eq!(run_test(code), "");
+}
+
+#[test]
+fn test_host_func() {
+    use nadesiko4::bytecode::NakoSystem;
+    use nadesiko4::error::ErrorCode;
+    use nadesiko4::value::Value;
+    let mut sys = NakoSystem::new();
+    sys.add_func("顧客情報取得", &[&["の", "を"]], |args| {
+        Ok(Value::from_string(format!("{}様の情報", args[0])))
+    });
+    sys.add_func("加算", &[&["に", "へ"], &["を"]], |args| {
+        let a = args[0].to_number().ok_or("数値ではありません")?;
+        let b = args[1].to_number().ok_or("数値ではありません")?;
+        Ok(Value::from_number(a + b))
+    });
+    let options = nadesiko4::NakoOptions::new();
+    let code = "「田中」の顧客情報取得を表示\n3を5に加算して表示";
+    let output = nadesiko4::run_easy_with_system(sys.clone(), code, &options).unwrap();
+    assert_eq!(output.trim(), "田中様の情報\n8");
+    // エラーはスクリプトのエラーとして報告される
+    let err = nadesiko4::run_easy_with_system(sys, "「あ」を5に加算", &options).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::SysFuncFailed);
+    assert_eq!(err.message(), "数値ではありません");
+}
+
+#[test]
+fn test_host_func_with_okurigana() {
+    use nadesiko4::bytecode::NakoSystem;
+    use nadesiko4::value::Value;
+    let mut sys = NakoSystem::new();
+    // 送り仮名つきの名前でも呼び出せる
+    sys.add_func("書き出す", &[&["を"]], |args| Ok(Value::from_string(format!("[{}]", args[0]))));
+    let options = nadesiko4::NakoOptions::new();
+    let output = nadesiko4::run_easy_with_system(sys, "「x」を書き出して表示
+「y」を書出して表示", &options).unwrap();
+    assert_eq!(output, "[x]\n[y]\n");
+}
+
+#[test]
+fn test_error_result() {
+    use nadesiko4::error::{ErrorCode, NakoError};
+    let options = nadesiko4::NakoOptions::new();
+    // 字句解析エラー
+    let err = nadesiko4::run_easy("「abc", &options).unwrap_err();
+    assert!(matches!(err, NakoError::Lexer(_)));
+    assert_eq!(err.code(), ErrorCode::UnclosedString);
+    // 文法エラー
+    let err = nadesiko4::run_easy("A=1\nもしAが1ならば\n「x」を表示", &options).unwrap_err();
+    assert!(matches!(err, NakoError::Parser(_)));
+    assert_eq!(err.code(), ErrorCode::MissingBlockEnd);
+    assert_eq!(err.pos().line, 1);
+    // コンパイルエラー
+    let err = nadesiko4::run_easy("抜ける", &options).unwrap_err();
+    assert!(matches!(err, NakoError::Compile(_)));
+    assert_eq!(err.code(), ErrorCode::BreakOutsideLoop);
+    // 実行時エラー
+    let err = nadesiko4::run_easy("A=0\n\n10/Aを表示", &options).unwrap_err();
+    assert!(matches!(err, NakoError::Runtime(_)));
+    assert_eq!(err.code(), ErrorCode::DivisionByZero);
+    assert_eq!(err.code().as_str(), "E0404");
+}
+
+#[test]
+fn test_parser_reports_all_errors() {
+    let options = nadesiko4::NakoOptions::new();
+    let code = "もしAが1ならば\n  B=\n  「x」を表示\nここまで\nC=1+\n「ok」を表示\nDを反復\n  E=(2\nここまで";
+    let err = nadesiko4::run_easy(code, &options).unwrap_err();
+    let lines: Vec<usize> = err.errors().iter().map(|e| e.pos().line).collect();
+    assert_eq!(lines, vec![1, 4, 7]);
+    assert_eq!(err.to_string().lines().count(), 3);
+}
+
+#[test]
+fn test_runtime_error_position() {
+    let options = nadesiko4::NakoOptions::new();
+    let sys = nadesiko4::compile("A=0\n「a」を表示\n10/Aを表示", &options).unwrap();
+    assert_eq!(sys.spans.len(), sys.codes.len());
+    // 失敗した式の位置(割り算の演算子)を指す
+    let err = nadesiko4::run_easy("A=0\n「a」を表示\n10/Aを表示", &options).unwrap_err();
+    assert_eq!((err.pos().line, err.pos().column), (2, 2));
+    // 関数の中のエラーは関数の中の位置を指す
+    let code = "●(Xを)割るとは\n  100/Xで戻る\nここまで\n0を割るを表示";
+    let err = nadesiko4::run_easy(code, &options).unwrap_err();
+    assert_eq!((err.pos().line, err.pos().column), (1, 5));
+}
+
+#[test]
+fn test_runtime_stack_trace() {
+    let options = nadesiko4::NakoOptions::new();
+    let code = "●(Xを)割るとは\n  100/Xで戻る\nここまで\n●(AとBを)試すとは\n  Aを割る\nここまで\n0と「あ」を試す";
+    let err = nadesiko4::run_easy(code, &options).unwrap_err();
+    let trace = err.trace();
+    assert_eq!(trace.len(), 2);
+    // 関数名は定義に書かれたまま(送り仮名つき)で表示する
+    assert_eq!(trace[0].func_name, "割る");
+    assert_eq!(trace[0].call_pos.line, 4);
+    assert_eq!(trace[0].args_text(), "0");
+    assert_eq!(trace[1].func_name, "試す");
+    assert_eq!(trace[1].call_pos.line, 6);
+    assert_eq!(trace[1].args_text(), "0, 「あ」");
+    let text = nadesiko4::diagnostic::render(&err, "test.nako4", code);
+    assert!(text.contains("『試す』 引数: 0, 「あ」 (7行目 7列目から呼び出し)"), "{}", text);
+    let code = "●(Aを)割り算するとは\n  100/Aで戻る\nここまで\n0を割り算するを表示";
+    let err = nadesiko4::run_easy(code, &options).unwrap_err();
+    let text = nadesiko4::diagnostic::render(&err, "test.nako4", code);
+    assert!(text.contains("『割り算する』 引数: 0"), "{}", text);
+    // 失敗したシステム関数も履歴に入る
+    let mut sys = nadesiko4::bytecode::NakoSystem::new();
+    sys.add_func("失敗", &[&["を"]], |_| Err("失敗しました".to_string()));
+    let err = nadesiko4::run_easy_with_system(sys, "●(Xを)包むとは\n  Xを失敗\nここまで\n5を包む", &options).unwrap_err();
+    let names: Vec<&str> = err.trace().iter().map(|f| f.func_name.as_str()).collect();
+    assert_eq!(names, vec!["失敗", "包む"]);
+}
+
+#[test]
+fn test_run_more() {
+    use nadesiko4::bytecode::NakoSystem;
+    use nadesiko4::error::ErrorCode;
+    let options = nadesiko4::NakoOptions::new();
+    let mut sys = NakoSystem::new();
+    let buffer = sys.capture_output();
+    nadesiko4::run_more(&mut sys, "A=3\n●(Nを)倍増とは\nN*2で戻る\nここまで\n「開始」を表示", &options).unwrap();
+    assert_eq!(buffer.take(), "開始\n");
+    // 変数と関数は引き継がれ、追加したコードだけが実行される
+    nadesiko4::run_more(&mut sys, "Aを倍増して表示", &options).unwrap();
+    assert_eq!(buffer.take(), "6\n");
+    // 関数を定義し直すと以後の呼び出しに反映される
+    nadesiko4::run_more(&mut sys, "●(Nを)倍増とは\nN*10で戻る\nここまで", &options).unwrap();
+    nadesiko4::run_more(&mut sys, "Aを倍増して表示", &options).unwrap();
+    assert_eq!(buffer.take(), "30\n");
+    // コンパイルに失敗したときは何も追加されない
+    let (len, var_len) = (sys.codes.len(), sys.var_table.len());
+    let err = nadesiko4::run_more(&mut sys, "新しい変数=1\n「途中」を表示\n抜ける", &options).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::BreakOutsideLoop);
+    assert_eq!(sys.codes.len(), len);
+    assert_eq!(sys.var_table.len(), var_len);
+    assert_eq!(sys.var_table.get_name_index("新しい変数"), None);
+    // 実行時エラーの後も続けて使える
+    assert!(nadesiko4::run_more(&mut sys, "A/0", &options).is_err());
+    nadesiko4::run_more(&mut sys, "A+1を表示", &options).unwrap();
+    assert_eq!(buffer.take(), "4\n");
+}
+
+#[test]
+fn test_compile_after_runtime_error() {
+    use nadesiko4::bytecode::NakoSystem;
+    let options = nadesiko4::NakoOptions::new();
+    let mut sys = NakoSystem::new();
+    let buffer = sys.capture_output();
+    let start = nadesiko4::compile_into(&mut sys, "1/0を表示", &options).unwrap();
+    assert!(nadesiko4::run_vm(&mut sys, start, &options).is_err());
+    // 実行時エラーが残っていてもコンパイルは失敗しない
+    assert!(sys.errors.is_empty());
+    let start = nadesiko4::compile_into(&mut sys, "「ok」を表示", &options).unwrap();
+    sys.stack.clear();
+    nadesiko4::run_vm(&mut sys, start, &options).unwrap();
+    assert_eq!(buffer.take(), "ok\n");
+}
+
+#[test]
+fn test_output_streaming() {
+    use std::sync::{Arc, Mutex};
+    use nadesiko4::bytecode::NakoSystem;
+    use nadesiko4::output::CallbackOutput;
+    use nadesiko4::value::Value;
+    let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut sys = NakoSystem::new();
+    let sink = lines.clone();
+    sys.set_output(CallbackOutput(move |text: &str| sink.lock().unwrap().push(text.to_string())));
+    // 表示した内容は実行の途中でもすぐ出力先に届く
+    let seen = lines.clone();
+    sys.add_func("行数", &[], move |_| Ok(Value::from_number(seen.lock().unwrap().len() as f64)));
+    nadesiko4::run_more(&mut sys, "「a」を表示\n行数を表示", &nadesiko4::NakoOptions::new()).unwrap();
+    assert_eq!(*lines.lock().unwrap(), vec!["a", "\n", "2", "\n"]);
+}
+
+#[test]
+fn test_input() {
+    use nadesiko4::bytecode::NakoSystem;
+    use nadesiko4::input::StringInput;
+    let mut sys = NakoSystem::new();
+    sys.set_input(StringInput::new("taro\n 42 \n1行目\n2行目\n3行目\n"));
+    let code = "「名前は？」と尋ねる\nそれを表示\n「年齢は？」と尋ねる\nそれ+1を表示\n標準入力取得を表示\n標準入力全取得を表示\n標準入力取得を表示";
+    let output = nadesiko4::run_easy_with_system(sys, code, &nadesiko4::NakoOptions::new()).unwrap();
+    assert_eq!(output, "名前は？taro\n年齢は？43\n1行目\n2行目\n3行目\n\n\n");
+}
+
+#[test]
+fn test_system_moves_to_thread() {
+    use nadesiko4::bytecode::NakoSystem;
+    use nadesiko4::input::StringInput;
+    use nadesiko4::value::Value;
+    // 入出力とホスト関数を設定したシステムを別スレッドに移して実行できる
+    let mut sys = NakoSystem::new();
+    sys.set_input(StringInput::new("5\n"));
+    sys.add_func("倍", &[&["を"]], |args| Ok(Value::from_number(args[0].to_number().unwrap_or(0.0) * 2.0)));
+    let buffer = sys.capture_output();
+    std::thread::spawn(move || {
+        nadesiko4::run_more(&mut sys, "「」と尋ねる\nそれを倍を表示", &nadesiko4::NakoOptions::new()).unwrap();
+    }).join().unwrap();
+    assert_eq!(buffer.take(), "10\n");
+}
+
+#[test]
+fn test_bytecode_file() {
+    use nadesiko4::bytecode::NakoSystem;
+    use nadesiko4::bytecode_file;
+    use nadesiko4::value::Value;
+    let code = "●(Nを)倍増とは\nN*2で戻る\nここまで\nIを1から3まで繰り返す\nIを倍増して表示\nここまで\n[1,「a」,[]]を表示\n5を二乗して表示";
+    let mut sys = NakoSystem::new();
+    sys.add_func("二乗", &[&["を"]], |args| Ok(Value::from_number(args[0].to_number().unwrap_or(0.0).powi(2))));
+    let options = nadesiko4::NakoOptions::new();
+    let compiled = nadesiko4::compile_with_system(sys.clone(), code, &options).unwrap();
+    let bytes = bytecode_file::to_bytes(&compiled).unwrap();
+    // ホスト関数は読み込み先のシステムに登録しておく
+    let mut loaded = bytecode_file::from_bytes_with(sys, &bytes).unwrap();
+    let buffer = loaded.capture_output();
+    nadesiko4::vm::run(&mut loaded).unwrap();
+    assert_eq!(buffer.take(), "2\n4\n6\n[1, a, []]\n25\n");
+    // 登録されていない関数を使うファイルは読み込めない
+    assert!(bytecode_file::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn test_disassemble_and_assemble() {
+    use nadesiko4::asm;
+    let code = "●(Nを)倍増とは\nN*2で戻る\nここまで\nIを1から3まで繰り返す\nもしIが2ならば続ける\nIを倍増して表示\nここまで\n[1,「a;\"b\"」]を表示";
+    let sys = nadesiko4::compile(code, &nadesiko4::NakoOptions::new()).unwrap();
+    let text = asm::disassemble(&sys, Some(code));
+    assert!(text.contains(".func 倍増 L0001 args=を locals=倍増:N,倍増:それ\n"));
+    assert!(text.contains(".line 6  ; Iを倍増して表示\n    PushVariable I\n    Call 倍増 1\n"));
+    // 逆アセンブルした結果をアセンブルすると同じ命令列になる
+    let mut loaded = asm::assemble(&text).unwrap();
+    assert_eq!(asm::disassemble(&loaded, Some(code)), text);
+    let buffer = loaded.capture_output();
+    nadesiko4::vm::run(&mut loaded).unwrap();
+    assert_eq!(buffer.take(), "2\n6\n[1, a;\"b\"]\n");
+}
+
+#[test]
+fn test_disassemble_full_func_names() {
+    use nadesiko4::asm;
+    // 送り仮名つきの関数名は書かれたまま表示し、アセンブルしても同じ関数を呼ぶ
+    let code = "●(Aを)割り算するとは\nA/2で戻る\nここまで\n「数は？」と尋ねる\nそれを割り算するを表示";
+    let sys = nadesiko4::compile(code, &nadesiko4::NakoOptions::new()).unwrap();
+    let text = asm::disassemble(&sys, None);
+    assert!(text.contains(".func 割り算する L0001 args=を "), "{}", text);
+    assert!(text.contains("    Call 尋ねる 1\n"), "{}", text);
+    assert!(text.contains("    Call 割り算する 1\n"), "{}", text);
+    let mut loaded = asm::assemble(&text).unwrap();
+    assert_eq!(asm::disassemble(&loaded, None), text);
+    loaded.set_input(nadesiko4::input::StringInput::new("8\n"));
+    let buffer = loaded.capture_output();
+    nadesiko4::vm::run(&mut loaded).unwrap();
+    assert_eq!(buffer.take(), "数は？4\n");
+}
+
+#[test]
+fn test_hand_written_assembly() {
+    let text = "
+        ; 1から3までの合計
+        PushConst 0
+        Let 合計
+        PushConst 1
+        Let I
+    loop:
+        PushVariable 合計
+        PushVariable I
+        Add
+        Let 合計
+        PushVariable I
+        PushConst 1
+        Add
+        Let I
+        PushVariable I
+        PushConst 3
+        Gt
+        JumpIfFalse loop
+        PushVariable 合計
+        Call 表示 1
+        Pop
+    .line 3
+        PushConst 1
+        PushConst 0
+        Div
+    ";
+    let mut sys = nadesiko4::asm::assemble(text).unwrap();
+    let buffer = sys.capture_output();
+    let err = nadesiko4::vm::run(&mut sys).unwrap_err();
+    assert_eq!(buffer.take(), "6\n");
+    assert_eq!(err.pos().line, 2);
+}
+
+#[test]
+fn test_verify_rejects_before_running() {
+    use nadesiko4::error::ErrorCode;
+    // 表示の後(4番目の命令)で定数の番号が壊れている
+    let mut sys = nadesiko4::asm::assemble("PushConst \"a\"\nCall 表示 1\nPop\nPushConst #7\nPop").unwrap();
+    let bytes = nadesiko4::bytecode_file::to_bytes(&sys).unwrap();
+    let buffer = sys.capture_output();
+    let err = nadesiko4::vm::run(&mut sys).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::BadOperandIndex);
+    assert_eq!(buffer.take(), "");
+    // ファイルから読み込んだコードも実行前に検証される
+    let mut loaded = nadesiko4::bytecode_file::from_bytes(&bytes).unwrap();
+    let err = nadesiko4::vm::run(&mut loaded).unwrap_err();
+    assert_eq!(err.to_string(), "[検証エラー][E0501] 1行目: 4番目の命令: 定数の番号7が不正です");
+}
+
+#[test]
+fn test_optimize_keeps_results() {
+    let mut options = nadesiko4::NakoOptions::new();
+    options.optimize = true;
+    let codes = [
+        "A=(1+2)*3\nAを表示\nもし2>1ならば「yes」を表示",
+        "●(Nを)倍増とは\nN*2で戻る\nここまで\n3回\n回数を倍増して表示\nここまで",
+        "A=0\nA<3の間\nA=A+1\nもしAが2ならば続ける\nAを表示\nここまで",
+        "[1,2,3]を反復\nもし対象が3ならば抜ける\n対象を表示\nここまで",
+        "1/0を表示",
+    ];
+    for code in codes {
+        let plain = nadesiko4::run_easy(code, &nadesiko4::NakoOptions::new()).map_err(|err| err.to_string());
+        let optimized = nadesiko4::run_easy(code, &options).map_err(|err| err.to_string());
+        assert_eq!(plain, optimized, "{}", code);
+    }
+}
+
+#[test]
+fn test_register_vm_matches_stack_vm() {
+    let codes = [
+        "A=1+2*3\nAを表示\n「a」+1を表示\n10/4を表示",
+        "S=0\n3回\n  2回\n    S=S+回数\n  ここまで\nここまで\nSを表示",
+        "Iを10から1まで3ずつ繰り返す\nIを表示\nここまで\nIを1から3まで繰り返す\nもしIが2ならば続ける\nIを表示\nここまで",
+        "A=0\nA<10の間\n  A=A+1\n  もしAが2ならば続ける\n  もしAが4ならば抜ける\n  Aを表示\nここまで",
+        "[「a」,「b」,[1,2]]を反復\n対象キーを表示\n対象を表示\nここまで\nA=[1+1, 「x」]\nAを表示",
+        "●(Nの)階乗とは\n  もしNが1以下ならば1で戻る\n  (N-1)の階乗*Nで戻る\nここまで\n5の階乗を表示\nX=(3の階乗)+(2の階乗)*10\nXを表示",
+        "A=100\n●(Xを)処理とは\n  A=X+1\n  それ=A*2\nここまで\n5を処理\nそれを表示\nAを表示",
+        "もし「1」が1ならば「同じ」を表示\n違えば「違う」を表示\nもし「b」>「a」ならば「大きい」を表示",
+        "A=0\n「a」を表示\n10/Aを表示",
+        "●(Xを)割るとは\n  100/Xで戻る\nここまで\n●(AとBを)試すとは\n  Aを割る\nここまで\n「前」を表示\n0と「あ」を試す",
+        "Iを1から3まで0ずつ繰り返す\nIを表示\nここまで",
+        "「x」回\n「あ」を表示\nここまで",
+    ];
+    let mut options = nadesiko4::NakoOptions::new();
+    options.register_vm = true;
+    for code in codes {
+        let mut stack = nadesiko4::compile(code, &nadesiko4::NakoOptions::new()).unwrap();
+        let mut register = stack.clone();
+        let (stack_out, register_out) = (stack.capture_output(), register.capture_output());
+        let stack_result = nadesiko4::run_vm(&mut stack, 0, &nadesiko4::NakoOptions::new());
+        let register_result = nadesiko4::run_vm(&mut register, 0, &options);
+        assert_eq!(stack_out.take(), register_out.take(), "{}", code);
+        assert_eq!(stack_result, register_result, "{}", code);
+    }
+    // 最適化したコードも同じ結果になる
+    options.optimize = true;
+    let code = "S=0\nIを1から100まで繰り返す\nもしIが(2*5)ならば続ける\nS=S+I*2\nここまで\nSを表示";
+    assert_eq!(nadesiko4::run_easy(code, &options), nadesiko4::run_easy(code, &nadesiko4::NakoOptions::new()));
+}